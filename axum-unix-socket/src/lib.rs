@@ -1,42 +1,522 @@
-use axum::{extract::Request, response::Response};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    response::Response,
+};
 use hyper::body::Incoming;
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server,
 };
-use std::{convert::Infallible, io, path::Path};
-use tokio::{fs, net::UnixListener};
+use std::{
+    convert::Infallible,
+    fs::Permissions,
+    future::{self, Future},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time,
+};
 use tower::Service;
 
+/// How long a shutdown waits for the open connections to finish before dropping them.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after `accept` fails for lack of resources, like open files, before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 pub async fn serve<S>(path: impl AsRef<Path>, app: S) -> io::Result<()>
 where
     S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
     S::Future: Send,
 {
-    let path = path.as_ref();
+    serve_with_shutdown(path, app, future::pending()).await
+}
+
+/// Serves until the `shutdown` future resolves. New connections are refused from then on and the
+/// socket file is removed, while the open ones get a brief moment to finish their requests.
+pub async fn serve_with_shutdown<S>(
+    path: impl AsRef<Path>,
+    app: S,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let listener = bind(path.as_ref(), None).await?;
+    serve_listener(
+        Some(path.as_ref()),
+        listener,
+        app,
+        Semaphore::MAX_PERMITS,
+        shutdown,
+    )
+    .await
+}
+
+/// Same as [`serve`], but over TCP, handy to poke at an app locally. Handlers can't extract
+/// [`PeerCredentials`] from TCP connections.
+pub async fn serve_tcp<S>(listener: TcpListener, app: S) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    serve_tcp_with_shutdown(listener, app, future::pending()).await
+}
+
+/// Same as [`serve_with_shutdown`], but over TCP.
+pub async fn serve_tcp_with_shutdown<S>(
+    listener: TcpListener,
+    app: S,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    serve_listener(None, listener, app, Semaphore::MAX_PERMITS, shutdown).await
+}
 
-    fs::remove_file(&path).await.ok();
+/// Like [`serve`], but sets the permissions of the socket file to `mode` (e.g. `0o666`) instead
+/// of leaving them to the umask, so processes of other users can connect.
+pub async fn serve_with_permissions<S>(path: impl AsRef<Path>, app: S, mode: u32) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let listener = bind(path.as_ref(), Some(mode)).await?;
+    serve_listener(
+        Some(path.as_ref()),
+        listener,
+        app,
+        Semaphore::MAX_PERMITS,
+        future::pending(),
+    )
+    .await
+}
+
+/// Like [`serve`], but keeps at most `max_connections` open at once. Further connections wait in
+/// the listener backlog until one of them is closed.
+pub async fn serve_with_limit<S>(
+    path: impl AsRef<Path>,
+    app: S,
+    max_connections: usize,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let listener = bind(path.as_ref(), None).await?;
+    serve_listener(
+        Some(path.as_ref()),
+        listener,
+        app,
+        max_connections,
+        future::pending(),
+    )
+    .await
+}
+
+/// Credentials of the process on the other end of the connection, read with `SO_PEERCRED` when
+/// it's accepted. Extract it in a handler to tell who is calling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not every platform reports the pid of the peer.
+    pub pid: Option<i32>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for PeerCredentials {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().copied().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "peer credentials are unavailable",
+        ))
+    }
+}
+
+/// A server running in the background, stopped with [`Server::stop`].
+pub struct Server {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl Server {
+    /// Binds the socket and starts serving, so it accepts connections as soon as this returns.
+    pub async fn start<S>(path: impl AsRef<Path>, app: S) -> io::Result<Self>
+    where
+        S: Service<Request<Incoming>, Response = Response, Error = Infallible>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+    {
+        let path = path.as_ref().to_path_buf();
+        let listener = bind(&path, None).await?;
+        let (shutdown, signal) = oneshot::channel();
+
+        let task = tokio::spawn(async move {
+            let signal = async {
+                signal.await.ok();
+            };
+            serve_listener(Some(&path), listener, app, Semaphore::MAX_PERMITS, signal).await
+        });
+
+        Ok(Self { shutdown, task })
+    }
+
+    /// Shuts the server down like [`serve_with_shutdown`] does, waiting until it's finished.
+    pub async fn stop(self) -> io::Result<()> {
+        self.shutdown.send(()).ok();
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+/// Binds a listener to the path, replacing any socket left behind by a previous run.
+async fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    fs::remove_file(path).await.ok();
 
     let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    }
+
+    Ok(listener)
+}
+
+/// Sockets the connection loop accepts connections from.
+trait Listener {
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
 
-    while let Ok((socket, _addr)) = listener.accept().await {
-        let service = app.clone();
+    async fn accept(&self) -> io::Result<Self::Io>;
 
-        tokio::spawn(async move {
-            let socket = TokioIo::new(socket);
+    fn peer_credentials(socket: &Self::Io) -> Option<PeerCredentials>;
+}
+
+impl Listener for UnixListener {
+    type Io = UnixStream;
+
+    async fn accept(&self) -> io::Result<UnixStream> {
+        let (socket, _addr) = UnixListener::accept(self).await?;
+        Ok(socket)
+    }
+
+    fn peer_credentials(socket: &UnixStream) -> Option<PeerCredentials> {
+        let credentials = socket.peer_cred().ok()?;
+        Some(PeerCredentials {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+            pid: credentials.pid(),
+        })
+    }
+}
 
-            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-                service.clone().call(request)
-            });
+impl Listener for TcpListener {
+    type Io = TcpStream;
 
-            if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(socket, hyper_service)
-                .await
-            {
-                eprintln!("failed to serve connection: {err:#}");
+    async fn accept(&self) -> io::Result<TcpStream> {
+        let (socket, _addr) = TcpListener::accept(self).await?;
+        socket.set_nodelay(true)?;
+        Ok(socket)
+    }
+
+    fn peer_credentials(_socket: &TcpStream) -> Option<PeerCredentials> {
+        None
+    }
+}
+
+/// Accepts connections until the shutdown, then drains them. The socket file at `path`, if any,
+/// is removed once the listener is closed.
+async fn serve_listener<L: Listener, S>(
+    path: Option<&Path>,
+    listener: L,
+    app: S,
+    max_connections: usize,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let permits = Arc::new(Semaphore::new(max_connections));
+    let (stop, stopped) = watch::channel(());
+    let mut connections = JoinSet::new();
+
+    tokio::pin!(shutdown);
+
+    let result = loop {
+        tokio::select! {
+            accepted = accept(&listener, &permits) => match accepted {
+                Ok((socket, permit)) => {
+                    let credentials = L::peer_credentials(&socket);
+                    let connection =
+                        serve_connection(socket, credentials, app.clone(), stopped.clone());
+                    connections.spawn(async move {
+                        connection.await;
+                        drop(permit);
+                    });
+                }
+                Err(err) => {
+                    if let Err(err) = handle_accept_error(err).await {
+                        break Err(err);
+                    }
+                }
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break Ok(()),
+        }
+    };
+
+    drop(listener);
+    if let Some(path) = path {
+        fs::remove_file(path).await.ok();
+    }
+
+    stop.send(()).ok();
+    time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await
+    .ok();
+
+    result
+}
+
+/// Waits for a free connection slot before accepting the next connection.
+async fn accept<L: Listener>(
+    listener: &L,
+    permits: &Arc<Semaphore>,
+) -> io::Result<(L::Io, OwnedSemaphorePermit)> {
+    let permit = permits
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("the semaphore is never closed");
+    let socket = listener.accept().await?;
+    Ok((socket, permit))
+}
+
+/// Logs errors from `accept` the listener can recover from, returning the ones it can't.
+async fn handle_accept_error(err: io::Error) -> io::Result<()> {
+    match err.kind() {
+        // The peer gave up before the connection was accepted, nothing to wait for
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => Ok(()),
+        // The listener itself is broken
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotConnected => Err(err),
+        _ => {
+            eprintln!("failed to accept connection: {err:#}");
+            time::sleep(ACCEPT_BACKOFF).await;
+            Ok(())
+        }
+    }
+}
+
+async fn serve_connection<I, S>(
+    socket: I,
+    credentials: Option<PeerCredentials>,
+    service: S,
+    mut stopped: watch::Receiver<()>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let socket = TokioIo::new(socket);
+
+    let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(credentials) = credentials {
+            request.extensions_mut().insert(credentials);
+        }
+        service.clone().call(request)
+    });
+
+    let builder = server::conn::auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(socket, hyper_service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = stopped.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("failed to serve connection: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{routing::get, Router};
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    use super::*;
+
+    async fn connect(path: &Path) -> UnixStream {
+        loop {
+            match UnixStream::connect(path).await {
+                Ok(stream) => return stream,
+                Err(_) => time::sleep(Duration::from_millis(10)).await,
             }
-        });
+        }
+    }
+
+    /// Sends a request over a new connection, which is kept open.
+    async fn request(path: &Path) -> (UnixStream, String) {
+        let mut stream = connect(path).await;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0; 1024];
+        let read = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..read]).into_owned();
+        (stream, response)
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_shutdown() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.socket");
+
+        let app = Router::new().route("/", get(|| async { "Rinha" }));
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_shutdown(path.clone(), app, async {
+            signal.await.ok();
+        }));
+
+        // The connection is kept alive and idle when the shutdown starts
+        let (mut stream, response) = request(&path).await;
+        assert!(response.ends_with("Rinha"));
+
+        shutdown.send(()).unwrap();
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(0, stream.read(&mut [0; 1024]).await.unwrap());
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_serve_with_permissions() {
+        let tmp = tempdir().unwrap();
+
+        for mode in [0o600, 0o666] {
+            let path = tmp.path().join(format!("{mode:o}.socket"));
+            let server = tokio::spawn(serve_with_permissions(path.clone(), Router::new(), mode));
+
+            // Requests are only served once the permissions are set
+            request(&path).await;
+            let permissions = std::fs::metadata(&path).unwrap().permissions();
+            assert_eq!(mode, permissions.mode() & 0o777);
+
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "Rinha" }));
+        let server = tokio::spawn(serve_tcp(listener, app));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("Rinha"));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_handle_accept_error() {
+        let too_many_open_files = io::Error::from_raw_os_error(24);
+        assert!(handle_accept_error(too_many_open_files).await.is_ok());
+
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert!(handle_accept_error(aborted).await.is_ok());
+
+        let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(handle_accept_error(invalid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limit() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.socket");
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/",
+            get({
+                let (active, peak) = (active.clone(), peak.clone());
+                || async move {
+                    let current = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(current, Ordering::SeqCst);
+                    time::sleep(Duration::from_millis(50)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    "Rinha"
+                }
+            }),
+        );
+        let server = tokio::spawn(serve_with_limit(path.clone(), app, 1));
+        connect(&path).await;
+
+        let requests = (0..3).map(|_| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                let mut stream = connect(&path).await;
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                assert!(response.ends_with("Rinha"));
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            request.await.unwrap();
+        }
+
+        assert_eq!(1, peak.load(Ordering::SeqCst));
+        server.abort();
+    }
 }
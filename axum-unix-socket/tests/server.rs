@@ -0,0 +1,61 @@
+use axum::{routing::get, Router};
+use axum_unix_socket::{PeerCredentials, Server};
+use std::os::unix::fs::MetadataExt;
+
+use tempfile::tempdir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+#[tokio::test]
+async fn test_start_and_stop() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("test.socket");
+
+    let app = Router::new().route("/", get(|| async { "Rinha" }));
+    let server = Server::start(&path, app).await.unwrap();
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("Rinha"));
+
+    server.stop().await.unwrap();
+    assert!(!path.exists());
+    assert!(UnixStream::connect(&path).await.is_err());
+}
+
+#[tokio::test]
+async fn test_peer_credentials() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("test.socket");
+
+    let app = Router::new().route(
+        "/",
+        get(|credentials: PeerCredentials| async move {
+            format!("{} {:?}", credentials.uid, credentials.pid)
+        }),
+    );
+    let server = Server::start(&path, app).await.unwrap();
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    // Files are owned by the uid of the process creating them
+    let uid = std::fs::metadata(tmp.path()).unwrap().uid();
+    let pid = std::process::id();
+    assert!(response.ends_with(&format!("{uid} Some({pid})")));
+
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,39 @@
+//! Scans 100k rows with and without the memory mapped reader.
+//!
+//! cargo bench -p espora-db --features mmap
+
+use std::time::{Duration, Instant};
+
+use espora_db::{codec::Bitcode, Db, DEFAULT_PAGE_SIZE};
+
+const ROWS: i64 = 100_000;
+const RUNS: u32 = 10;
+
+fn scan(mmap: bool, path: &std::path::Path) -> Duration {
+    let mut db = Db::<(i64, String), 64>::builder()
+        .mmap(mmap)
+        .build::<(i64, String), 64, DEFAULT_PAGE_SIZE, Bitcode>(path)
+        .unwrap();
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        assert_eq!(ROWS as usize, db.rows().filter(Result::is_ok).count());
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("scan.espora");
+
+    let mut db = Db::<(i64, String), 64>::builder()
+        .sync_writes(false)
+        .build::<(i64, String), 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+        .unwrap();
+    for i in 0..ROWS {
+        db.insert((i, format!("Rinha {i}"))).unwrap();
+    }
+
+    println!("read: {:?}", scan(false, &path));
+    println!("mmap: {:?}", scan(true, &path));
+}
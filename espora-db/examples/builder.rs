@@ -0,0 +1,57 @@
+//! Opens a database with each of the builder options.
+//!
+//! cargo run -p espora-db --example builder --all-features
+
+use std::time::Duration;
+
+use espora_db::{codec::Bitcode, Db, DEFAULT_PAGE_SIZE};
+
+type Row = (i64, String);
+
+fn main() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("builder.espora");
+
+    let builder = Db::<Row, 64>::builder()
+        .sync_writes(false)
+        .sync_write_interval(Duration::from_millis(10))
+        .create_if_missing(true)
+        .preallocate(64 * 1024)
+        .page_cache(16);
+
+    #[cfg(feature = "encryption")]
+    let builder = builder.encrypt([42; 32]);
+
+    #[cfg(feature = "mmap")]
+    let builder = builder.mmap(true);
+
+    let mut db = builder
+        .build::<Row, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+        .unwrap();
+    db.insert((1, String::from("Rinha"))).unwrap();
+    println!("sync: {} rows", db.count().unwrap());
+    drop(db);
+
+    let builder = Db::<Row, 64>::builder().read_only(true);
+
+    #[cfg(feature = "encryption")]
+    let builder = builder.encrypt([42; 32]);
+
+    let mut db = builder
+        .build::<Row, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+        .unwrap();
+    println!("read only: {} rows", db.count().unwrap());
+
+    #[cfg(feature = "tokio")]
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let path = tmp.path().join("builder-tokio.espora");
+        let mut db = Db::<Row, 64>::builder()
+            .sync_writes(true)
+            .group_commit(Duration::from_millis(1))
+            .build_tokio::<Row, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .await
+            .unwrap();
+        db.insert((1, String::from("Rinha"))).await.unwrap();
+        println!("tokio: {} rows", db.count().await.unwrap());
+    });
+}
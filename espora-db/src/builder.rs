@@ -2,54 +2,134 @@ use std::{io, path::Path, time::Duration};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::Db;
+use crate::{codec::Codec, Db};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Builder {
-    sync_writes: Option<Duration>,
+    pub(crate) sync_writes: Option<Duration>,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) preallocate: u64,
+    pub(crate) page_cache: usize,
+    #[cfg(feature = "encryption")]
+    pub(crate) cipher: Option<crate::format::PageCipher>,
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap: bool,
+    #[cfg(feature = "tokio")]
+    pub(crate) group_commit: Option<crate::tokio::GroupCommit>,
 }
 
 impl Default for Builder {
     fn default() -> Self {
         Builder {
             sync_writes: Some(Duration::from_secs(0)),
+            read_only: false,
+            create_if_missing: true,
+            preallocate: 0,
+            page_cache: 0,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            #[cfg(feature = "tokio")]
+            group_commit: None,
         }
     }
 }
 
 impl Builder {
-    pub fn sync_writes(self, sync_writes: bool) -> Self {
-        Self {
-            sync_writes: if sync_writes {
-                Some(Duration::from_secs(0))
-            } else {
-                None
-            },
-        }
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = if sync_writes {
+            Some(Duration::from_secs(0))
+        } else {
+            None
+        };
+        self
     }
 
-    pub fn sync_write_interval(self, interval: Duration) -> Self {
-        Self {
-            sync_writes: Some(interval),
-        }
+    pub fn sync_write_interval(mut self, interval: Duration) -> Self {
+        self.sync_writes = Some(interval);
+        self
+    }
+
+    /// Opens the file without write access, so a missing file is an error instead of being
+    /// created, and every write fails with [`crate::Error::ReadOnly`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether a missing file is created as an empty database, which is the default. Otherwise
+    /// opening it fails with [`std::io::ErrorKind::NotFound`].
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Grows the file to at least the given size when opening it, so inserts fill the reserved
+    /// space instead of extending the file page by page.
+    pub fn preallocate(mut self, bytes: u64) -> Self {
+        self.preallocate = bytes;
+        self
+    }
+
+    /// Keeps up to the given number of recently read pages in memory, so scans going over the same
+    /// pages again, like reading the newest rows, don't read them from the file. The cache is
+    /// dropped on every write and lock, so rows written by other handles may only be seen after
+    /// [`Db::lock_writes`]. Only affects the sync database.
+    pub fn page_cache(mut self, pages: usize) -> Self {
+        self.page_cache = pages;
+        self
+    }
+
+    /// Encrypts every page written to disk with the given key. Opening a file with a different
+    /// key fails instead of yielding garbage rows.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt(mut self, key: [u8; 32]) -> Self {
+        self.cipher = Some(crate::format::PageCipher::new(key));
+        self
+    }
+
+    /// Scans pages through a memory map of the file instead of one read per page. Only affects
+    /// the sync database.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Makes inserts on the tokio database wait for a sync shared with every database built from
+    /// clones of this builder, taken once the window elapses. Overrides `sync_writes`.
+    #[cfg(feature = "tokio")]
+    pub fn group_commit(mut self, window: Duration) -> Self {
+        self.group_commit = Some(crate::tokio::GroupCommit::new(window));
+        self
     }
 
-    pub fn build<T: Serialize + DeserializeOwned, const ROW_SIZE: usize>(
+    /// Opens the database, with `C` choosing how rows are serialized (see [`crate::codec`]), so
+    /// the same builder can open databases with different codecs.
+    pub fn build<
+        T: Serialize + DeserializeOwned,
+        const ROW_SIZE: usize,
+        const PAGE_SIZE: usize,
+        C: Codec,
+    >(
         self,
         path: impl AsRef<Path>,
-    ) -> io::Result<Db<T, ROW_SIZE>> {
-        let mut db = Db::from_path(path)?;
-        db.sync_writes = self.sync_writes;
-        Ok(db)
+    ) -> io::Result<Db<T, ROW_SIZE, PAGE_SIZE, C>> {
+        Db::open(path, self)
     }
 
     #[cfg(feature = "tokio")]
-    pub async fn build_tokio<T: Serialize + DeserializeOwned, const ROW_SIZE: usize>(
+    pub async fn build_tokio<
+        T: Serialize + DeserializeOwned,
+        const ROW_SIZE: usize,
+        const PAGE_SIZE: usize,
+        C: Codec,
+    >(
         self,
         path: impl AsRef<Path>,
-    ) -> io::Result<crate::tokio::Db<T, ROW_SIZE>> {
-        let mut db = crate::tokio::Db::from_path(path).await?;
-        db.sync_writes = self.sync_writes;
-        Ok(db)
+    ) -> io::Result<crate::tokio::Db<T, ROW_SIZE, PAGE_SIZE, C>> {
+        crate::tokio::Db::open(path, self).await
     }
 }
@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+
+use crate::page::Page;
+
+/// The most recently read pages by their offset, evicting the least recently used one once
+/// `capacity` pages are kept. A capacity of zero disables the cache.
+#[derive(Debug, Default)]
+pub(crate) struct PageCache<const ROW_SIZE: usize, const PAGE_SIZE: usize> {
+    capacity: usize,
+    pages: VecDeque<(u64, Page<ROW_SIZE, PAGE_SIZE>)>,
+}
+
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize> PageCache<ROW_SIZE, PAGE_SIZE> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<Page<ROW_SIZE, PAGE_SIZE>> {
+        let index = self
+            .pages
+            .iter()
+            .position(|(cached, _)| *cached == offset)?;
+        let entry = self.pages.remove(index)?;
+        let page = entry.1.clone();
+        self.pages.push_front(entry);
+        Some(page)
+    }
+
+    pub fn insert(&mut self, offset: u64, page: &Page<ROW_SIZE, PAGE_SIZE>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.pages.retain(|(cached, _)| *cached != offset);
+        self.pages.truncate(self.capacity - 1);
+        self.pages.push_front((offset, page.clone()));
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(row: i64) -> Page<1024> {
+        let mut page = Page::new();
+        page.insert(&bitcode::serialize(&row).unwrap()).unwrap();
+        page
+    }
+
+    fn row(page: Page<1024>) -> i64 {
+        bitcode::deserialize(page.rows().next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut cache = PageCache::new(2);
+        cache.insert(0, &page(1));
+        cache.insert(4096, &page(2));
+        assert_eq!(Some(1), cache.get(0).map(row));
+
+        cache.insert(8192, &page(3));
+        assert!(cache.get(4096).is_none());
+        assert_eq!(Some(1), cache.get(0).map(row));
+        assert_eq!(Some(3), cache.get(8192).map(row));
+
+        cache.clear();
+        assert!(cache.get(0).is_none());
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let mut cache = PageCache::new(0);
+        cache.insert(0, &page(1));
+        assert!(cache.get(0).is_none());
+    }
+}
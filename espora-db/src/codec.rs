@@ -0,0 +1,113 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::Error;
+
+/// Serialization format used to encode rows into their slots.
+pub trait Codec {
+    fn serialize<T: Serialize>(row: &T) -> Result<Vec<u8>, Error>;
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// Compact binary encoding backed by `bitcode`. This is the default codec.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bitcode;
+
+impl Codec for Bitcode {
+    fn serialize<T: Serialize>(row: &T) -> Result<Vec<u8>, Error> {
+        Ok(bitcode::serialize(row)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        Ok(bitcode::deserialize(bytes)?)
+    }
+}
+
+/// Plain JSON rows, handy to inspect a database file with a text editor.
+#[cfg(feature = "json")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn serialize<T: Serialize>(row: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(row).map_err(|err| Error::Serialization(Box::new(err)))
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(|err| Error::Serialization(Box::new(err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitcode_round_trip() {
+        let row = (1_i64, String::from("Rinha"));
+        let bytes = Bitcode::serialize(&row).unwrap();
+        assert_eq!(row, Bitcode::deserialize::<(i64, String)>(&bytes).unwrap());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_rows_on_disk() {
+        use tempfile::tempdir;
+
+        use crate::{Db, DbResult};
+
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<(i64, String), 64, 4096, Json>::from_path(&path).unwrap();
+
+        db.insert((1, String::from("Rinha"))).unwrap();
+        db.insert((2, String::from("Backend"))).unwrap();
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            vec![(1, String::from("Rinha")), (2, String::from("Backend"))],
+            rows
+        );
+
+        let bytes = std::fs::read(&path).unwrap();
+        let contents = String::from_utf8_lossy(&bytes);
+        assert!(contents.contains(r#"[1,"Rinha"]"#));
+        assert!(contents.contains(r#"[2,"Backend"]"#));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_build_with_codec() {
+        use tempfile::tempdir;
+
+        use crate::{Db, DbResult};
+
+        let tmp = tempdir().unwrap();
+        let builder = Db::<i64, 64>::builder().sync_writes(false);
+
+        let mut json = builder
+            .clone()
+            .build::<i64, 64, 4096, Json>(tmp.path().join("json.espora"))
+            .unwrap();
+        let mut binary = builder
+            .build::<i64, 64, 4096, Bitcode>(tmp.path().join("bitcode.espora"))
+            .unwrap();
+
+        json.insert(1_234_567).unwrap();
+        binary.insert(1_234_567).unwrap();
+        assert_eq!(
+            vec![1_234_567],
+            json.rows().collect::<DbResult<Vec<_>>>().unwrap()
+        );
+        assert_eq!(
+            vec![1_234_567],
+            binary.rows().collect::<DbResult<Vec<_>>>().unwrap()
+        );
+
+        let json = std::fs::read(tmp.path().join("json.espora")).unwrap();
+        let binary = std::fs::read(tmp.path().join("bitcode.espora")).unwrap();
+        assert!(String::from_utf8_lossy(&json).contains("1234567"));
+        assert!(!String::from_utf8_lossy(&binary).contains("1234567"));
+    }
+}
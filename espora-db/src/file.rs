@@ -0,0 +1,83 @@
+//! Positional reads and writes, using `pread`/`pwrite` on Unix and `seek_read`/`seek_write` on
+//! Windows.
+
+use std::{fs::File, io};
+
+/// Reads and writes at an offset without moving the cursor of the file, which the appends rely on.
+pub(crate) trait FileExt {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl FileExt for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl FileExt for File {
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        keep_position(self, move |file| {
+            while !buf.is_empty() {
+                match file.seek_read(buf, offset) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "failed to fill whole buffer",
+                        ))
+                    }
+                    Ok(read) => {
+                        buf = &mut buf[read..];
+                        offset += read as u64;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        keep_position(self, move |file| {
+            while !buf.is_empty() {
+                match file.seek_write(buf, offset) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "failed to write whole buffer",
+                        ))
+                    }
+                    Ok(written) => {
+                        buf = &buf[written..];
+                        offset += written as u64;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Puts the cursor back after `f`, as unlike `pread` and `pwrite` the Windows calls move it.
+#[cfg(windows)]
+fn keep_position<T>(mut file: &File, f: impl FnOnce(&File) -> io::Result<T>) -> io::Result<T> {
+    use std::io::Seek;
+
+    let position = file.stream_position()?;
+    let result = f(file);
+    file.seek(io::SeekFrom::Start(position))?;
+    result
+}
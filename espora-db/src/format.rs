@@ -0,0 +1,294 @@
+use std::io;
+
+use crate::page::{Page, RowLayout, SizePrefix};
+
+const MAGIC: &[u8; 8] = b"ESPORADB";
+const VERSION: u16 = 2;
+/// Files written before rows had varint size prefixes.
+const FIXED_PREFIX_VERSION: u16 = 1;
+
+/// Magic, version, flags, row size and page size.
+pub(crate) const HEADER_LEN: usize = 8 + 2 + 2 + 4 + 4;
+
+/// Rows of files written before there were headers, and so before the varint size prefixes and
+/// checksums.
+const HEADERLESS_LAYOUT: RowLayout = RowLayout {
+    prefix: SizePrefix::Fixed,
+    checksums: false,
+};
+
+const ENCRYPTED: u16 = 1;
+const CHECKSUMS: u16 = 1 << 1;
+
+/// How pages are laid out on disk. Shared by the sync and async databases, so every page read or
+/// written goes through the same transformations.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PageFormat<const PAGE_SIZE: usize> {
+    #[cfg(feature = "encryption")]
+    pub(crate) cipher: Option<PageCipher>,
+    /// Taken from the header of existing files, so older versions and files written with or
+    /// without the `crc` feature are still read and written.
+    pub(crate) layout: RowLayout,
+    /// Files written before there were headers start right at their first page.
+    pub(crate) headerless: bool,
+}
+
+impl<const PAGE_SIZE: usize> PageFormat<PAGE_SIZE> {
+    /// Size of a page on disk.
+    pub fn stride(&self) -> usize {
+        #[cfg(feature = "encryption")]
+        if self.cipher.is_some() {
+            return PAGE_SIZE + PageCipher::OVERHEAD;
+        }
+
+        PAGE_SIZE
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.cipher.is_some();
+
+        #[cfg(not(feature = "encryption"))]
+        false
+    }
+
+    /// Bytes reserved for the header at the start of the file, a whole page so the ones after it
+    /// stay aligned.
+    pub fn header_size(&self) -> u64 {
+        if self.headerless {
+            0
+        } else {
+            PAGE_SIZE as u64
+        }
+    }
+
+    /// Translates the offset of a page as seen by the rows into its position in the file.
+    pub fn physical_offset(&self, offset: u64) -> u64 {
+        self.header_size() + offset / PAGE_SIZE as u64 * self.stride() as u64
+    }
+
+    /// Inverse of [`PageFormat::physical_offset`].
+    pub fn logical_offset(&self, offset: u64) -> u64 {
+        offset.saturating_sub(self.header_size()) / self.stride() as u64 * PAGE_SIZE as u64
+    }
+
+    /// Rounds a file length down to the end of its last complete page.
+    pub fn last_page_boundary(&self, len: u64) -> u64 {
+        let pages = len.saturating_sub(self.header_size());
+        self.header_size() + pages - pages % self.stride() as u64
+    }
+
+    /// Number of complete pages in a file of the given length.
+    pub fn page_count(&self, len: u64) -> u64 {
+        len.saturating_sub(self.header_size()) / self.stride() as u64
+    }
+
+    /// Position of the page right before the one starting at `offset`, if there's any.
+    pub fn previous_page(&self, offset: u64) -> Option<u64> {
+        offset
+            .checked_sub(self.stride() as u64)
+            .filter(|offset| *offset >= self.header_size())
+    }
+
+    fn version(&self) -> u16 {
+        match self.layout.prefix {
+            SizePrefix::Fixed => FIXED_PREFIX_VERSION,
+            SizePrefix::Varint => VERSION,
+        }
+    }
+
+    fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.is_encrypted() {
+            flags |= ENCRYPTED;
+        }
+        if self.layout.checksums {
+            flags |= CHECKSUMS;
+        }
+        flags
+    }
+
+    /// The header written to new files, padded to [`PageFormat::header_size`].
+    pub fn header<const ROW_SIZE: usize>(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(PAGE_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.version().to_be_bytes());
+        header.extend_from_slice(&self.flags().to_be_bytes());
+        header.extend_from_slice(&(ROW_SIZE as u32).to_be_bytes());
+        header.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+        header.resize(self.header_size() as usize, 0);
+        header
+    }
+
+    /// Makes sure a file header was written with the same layout this format reads, adopting the
+    /// row size prefix of its version and whether its rows have checksums. Files without a header
+    /// are taken as written before there were any, as long as they start with a sane row.
+    pub fn check_header<const ROW_SIZE: usize>(&mut self, header: &[u8]) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+        if header.len() < HEADER_LEN || &header[..8] != MAGIC {
+            if !Self::is_headerless::<ROW_SIZE>(header) {
+                return invalid(String::from("not an espora database"));
+            }
+            if self.is_encrypted() {
+                return invalid(String::from("database isn't encrypted"));
+            }
+            self.headerless = true;
+            self.layout = HEADERLESS_LAYOUT;
+            return Ok(());
+        }
+
+        let field = |at: usize, len: usize| {
+            header[at..at + len]
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as usize)
+        };
+
+        self.layout.prefix = match field(8, 2) as u16 {
+            FIXED_PREFIX_VERSION => SizePrefix::Fixed,
+            VERSION => SizePrefix::Varint,
+            version => return invalid(format!("unsupported format version {version}")),
+        };
+
+        let row_size = field(12, 4);
+        if row_size != ROW_SIZE {
+            return invalid(format!(
+                "database has rows of {row_size} bytes, opened with {ROW_SIZE}"
+            ));
+        }
+
+        let page_size = field(16, 4);
+        if page_size != PAGE_SIZE {
+            return invalid(format!(
+                "database has pages of {page_size} bytes, opened with {PAGE_SIZE}"
+            ));
+        }
+
+        let flags = field(10, 2) as u16;
+        if flags & ENCRYPTED != self.flags() & ENCRYPTED {
+            let message = if self.is_encrypted() {
+                "database isn't encrypted"
+            } else {
+                "database is encrypted"
+            };
+            return invalid(String::from(message));
+        }
+        self.layout.checksums = flags & CHECKSUMS != 0;
+
+        Ok(())
+    }
+
+    /// Whether the file starts with a row laid out like files without a header, with a fixed size
+    /// prefix and no checksum.
+    fn is_headerless<const ROW_SIZE: usize>(start: &[u8]) -> bool {
+        if start.len() < ROW_SIZE {
+            return false;
+        }
+        HEADERLESS_LAYOUT.holds_row(&start[..ROW_SIZE])
+    }
+
+    /// An empty page laid out for this format.
+    pub fn new_page<const ROW_SIZE: usize>(&self) -> Page<ROW_SIZE, PAGE_SIZE> {
+        Page::with_layout(self.layout)
+    }
+
+    /// Serializes a page into the bytes written to disk, padded to the whole page.
+    pub fn seal<const ROW_SIZE: usize>(&self, page: &Page<ROW_SIZE, PAGE_SIZE>) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PAGE_SIZE);
+        bytes.extend_from_slice(page.as_ref());
+        bytes.resize(bytes.len() + PAGE_SIZE - page.len(), 0);
+
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.seal(&bytes);
+        }
+
+        bytes
+    }
+
+    /// Reads back a page written by [`PageFormat::seal`].
+    pub fn open<const ROW_SIZE: usize>(
+        &self,
+        bytes: &[u8],
+    ) -> io::Result<Page<ROW_SIZE, PAGE_SIZE>> {
+        // Zeroed pages were never written, as happens to the space reserved by preallocation
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            if bytes.iter().all(|byte| *byte == 0) {
+                return Ok(self.new_page());
+            }
+            return Ok(Page::from_bytes_with_layout(
+                cipher.open(bytes)?,
+                self.layout,
+            ));
+        }
+
+        Ok(Page::from_bytes_with_layout(bytes.to_vec(), self.layout))
+    }
+
+    /// Same as [`PageFormat::open`] for the page at `offset` of the map, which unencrypted pages
+    /// keep reading from instead of copying it.
+    #[cfg(feature = "mmap")]
+    pub fn open_mapped<const ROW_SIZE: usize>(
+        &self,
+        map: &std::sync::Arc<memmap2::Mmap>,
+        offset: usize,
+    ) -> io::Result<Page<ROW_SIZE, PAGE_SIZE>> {
+        if self.is_encrypted() {
+            return self.open(&map[offset..offset + self.stride()]);
+        }
+        Ok(Page::from_map(map.clone(), offset, self.layout))
+    }
+}
+
+/// Encrypts whole pages with ChaCha20-Poly1305. Each page is stored as a random nonce followed by
+/// the ciphertext and its authentication tag, so tampered pages or a wrong key are detected.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct PageCipher(chacha20poly1305::ChaCha20Poly1305);
+
+#[cfg(feature = "encryption")]
+impl PageCipher {
+    const NONCE_SIZE: usize = 12;
+    const TAG_SIZE: usize = 16;
+    pub const OVERHEAD: usize = Self::NONCE_SIZE + Self::TAG_SIZE;
+
+    pub fn new(key: [u8; 32]) -> Self {
+        use chacha20poly1305::KeyInit;
+        Self(chacha20poly1305::ChaCha20Poly1305::new(&key.into()))
+    }
+
+    pub fn seal(&self, page: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::{
+            aead::{Aead, AeadCore, OsRng},
+            ChaCha20Poly1305,
+        };
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, page)
+            .expect("encrypting a page can't fail");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    pub fn open(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        use chacha20poly1305::{aead::Aead, Nonce};
+
+        if bytes.len() < Self::OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated page"));
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(Self::NONCE_SIZE);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "couldn't decrypt page"))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for PageCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PageCipher(..)")
+    }
+}
@@ -1,38 +1,72 @@
 use std::{
+    collections::HashMap,
     error, fmt,
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
+    hash::Hash,
     io::{self, Read, Seek, Write},
     iter,
     marker::PhantomData,
-    os::fd::AsRawFd,
-    path::Path,
-    time::{Duration, Instant},
+    ops::Deref,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use lock::LockHandle;
+use lock::LockMode;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     builder::Builder,
-    page::{Page, PAGE_SIZE},
+    cache::PageCache,
+    codec::{Bitcode, Codec},
+    file::FileExt,
+    format::{PageFormat, HEADER_LEN},
+    page::{Page, RowLayout},
 };
 
 pub mod builder;
+mod cache;
+pub mod codec;
+mod file;
+mod format;
 mod lock;
 mod page;
 #[cfg(feature = "tokio")]
 pub mod tokio;
 
+pub use lock::LockHandle;
+pub use page::DEFAULT_PAGE_SIZE;
+
+/// Longest wait between attempts of [`Db::lock_writes_timeout`].
+pub(crate) const MAX_LOCK_BACKOFF: Duration = Duration::from_millis(50);
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Serialization(Box<dyn error::Error + Send + Sync>),
+    Corrupt { offset: u64 },
+    NotFound,
+    RowTooLarge { size: usize, max: usize },
+    WouldBlock,
+    ReadOnly,
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Io(err) => write!(f, "{err}"),
             Self::Serialization(err) => write!(f, "{err}"),
+            Self::Corrupt { offset } => write!(f, "corrupted row at offset {offset}"),
+            Self::NotFound => write!(f, "row not found"),
+            Self::RowTooLarge { size, max } => {
+                write!(
+                    f,
+                    "row of {size} bytes doesn't fit in a slot of {max} bytes"
+                )
+            }
+            Self::WouldBlock => write!(f, "database is locked by another handle"),
+            Self::ReadOnly => write!(f, "database was opened as read only"),
         }
     }
 }
@@ -53,136 +87,968 @@ impl From<bitcode::Error> for Error {
 
 pub(crate) type DbResult<T> = Result<T, Error>;
 
-pub struct Db<T, const ROW_SIZE: usize> {
-    current_page: Page<ROW_SIZE>,
+pub struct Db<
+    T,
+    const ROW_SIZE: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    C: Codec = Bitcode,
+> {
+    current_page: Page<ROW_SIZE, PAGE_SIZE>,
+    format: PageFormat<PAGE_SIZE>,
+    path: PathBuf,
     reader: File,
     writer: File,
     last_sync: Instant,
     pub(crate) sync_writes: Option<Duration>,
+    read_only: bool,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    /// Mapping of the reader shared by the scans, replaced once the file changes size.
+    #[cfg(feature = "mmap")]
+    map: Option<Arc<memmap2::Mmap>>,
+    page_cache: PageCache<ROW_SIZE, PAGE_SIZE>,
+    expiry: Option<Expiry<T>>,
     data: PhantomData<T>,
+    codec: PhantomData<C>,
+}
+
+/// How long rows are kept around, see [`Db::expire_after`].
+struct Expiry<T> {
+    ttl: Duration,
+    created_at: Arc<dyn Fn(&T) -> SystemTime + Send + Sync>,
 }
 
-impl<const ROW_SIZE: usize, T: Serialize + DeserializeOwned> Db<T, ROW_SIZE> {
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize, T: Serialize + DeserializeOwned, C: Codec>
+    Db<T, ROW_SIZE, PAGE_SIZE, C>
+{
     pub fn builder() -> Builder {
         Builder::default()
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path, Builder::default())
+    }
+
+    /// Opens an existing database for reading only. Every write fails with [`Error::ReadOnly`].
+    pub fn open_read_only(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path, Builder::default().read_only(true))
+    }
+
+    pub(crate) fn open(path: impl AsRef<Path>, builder: Builder) -> io::Result<Self> {
         let mut file = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
+            .write(!builder.read_only)
+            .create(builder.create_if_missing && !builder.read_only)
+            .truncate(false)
             .open(&path)?;
 
-        let current_page = if file.seek(io::SeekFrom::End(-(PAGE_SIZE as i64))).is_ok() {
-            let mut buf = vec![0; PAGE_SIZE];
-            file.read_exact(&mut buf)?;
-            file.seek(io::SeekFrom::End(-(PAGE_SIZE as i64)))?;
-            Page::from_bytes(buf)
-        } else {
-            file.seek(io::SeekFrom::End(0))?;
-            Page::new()
+        let mut format = PageFormat {
+            #[cfg(feature = "encryption")]
+            cipher: builder.cipher,
+            layout: RowLayout::default(),
+            headerless: false,
         };
 
+        let len = file.metadata()?.len();
+        if len == 0 && !builder.read_only {
+            file.write_all_at(&format.header::<ROW_SIZE>(), 0)?;
+        } else {
+            // Headerless files are recognized by their first row
+            let mut header = vec![0; HEADER_LEN.max(ROW_SIZE).min(len as usize)];
+            file.read_exact_at(&mut header, 0)?;
+            format.check_header::<ROW_SIZE>(&header)?;
+        }
+
+        if !builder.read_only && file.metadata()?.len() < builder.preallocate {
+            file.set_len(builder.preallocate)?;
+        }
+
+        let current_page = last_page(&mut file, &format)?;
+
         Ok(Self {
             current_page,
+            format,
+            path: path.as_ref().to_path_buf(),
             reader: File::open(&path)?,
             writer: file,
             last_sync: Instant::now(),
-            sync_writes: Some(Duration::from_secs(0)),
+            sync_writes: builder.sync_writes,
+            read_only: builder.read_only,
+            #[cfg(feature = "mmap")]
+            mmap: builder.mmap,
+            #[cfg(feature = "mmap")]
+            map: None,
+            page_cache: PageCache::new(builder.page_cache),
+            expiry: None,
             data: PhantomData,
+            codec: PhantomData,
         })
     }
 
-    pub fn insert(&mut self, row: T) -> DbResult<()> {
-        self.current_page.insert(row)?;
+    /// Inserts a row, returning its byte offset in the file.
+    pub fn insert(&mut self, row: T) -> DbResult<u64> {
+        let offset =
+            self.current_page_offset()? + (PAGE_SIZE - self.current_page.free_bytes()) as u64;
+        self.insert_many(iter::once(row))?;
+        Ok(offset)
+    }
 
-        self.writer.write_all(
-            &[
-                self.current_page.as_ref(),
-                &vec![0; PAGE_SIZE - self.current_page.len()],
-            ]
-            .concat(),
-        )?;
+    /// Inserts all the rows, writing each page once it is full and syncing only at the end.
+    ///
+    /// If a row fails to serialize, the rows before it are still written.
+    pub fn insert_many(&mut self, rows: impl IntoIterator<Item = T>) -> DbResult<()> {
+        self.page_cache.clear();
+        self.check_writable()?;
 
-        match self.sync_writes {
-            Some(interval) if self.last_sync.elapsed() > interval => {
-                self.writer.sync_data()?;
-                self.last_sync = Instant::now();
+        let mut pending = false;
+        let mut result = Ok(());
+
+        for row in rows {
+            if let Err(err) = C::serialize(&row).and_then(|row| self.current_page.insert(&row)) {
+                result = Err(err);
+                break;
+            }
+            pending = true;
+
+            if self.current_page.is_full() {
+                self.writer
+                    .write_all(&self.format.seal(&self.current_page))?;
+                self.current_page = self.format.new_page();
+                pending = false;
+            }
+        }
+
+        // The page being filled is written too, and rewritten in place by the next insert
+        if pending {
+            self.writer
+                .write_all(&self.format.seal(&self.current_page))?;
+            self.writer
+                .seek(io::SeekFrom::Current(-(self.format.stride() as i64)))?;
+        }
+
+        self.sync_if_needed()?;
+
+        result
+    }
+
+    /// Same as [`Db::insert_many`], but without syncing until every row is written, whatever
+    /// `sync_writes` is set to, and then syncing once. Returns how many rows were written.
+    pub fn import(&mut self, rows: impl IntoIterator<Item = T>) -> DbResult<usize> {
+        let mut count = 0;
+        let sync_writes = self.sync_writes.take();
+        let result = self.insert_many(rows.into_iter().inspect(|_| count += 1));
+        self.sync_writes = sync_writes;
+
+        result?;
+        self.sync()?;
+        Ok(count)
+    }
+
+    /// Marks every row matching the predicate as deleted, returning how many rows were removed.
+    ///
+    /// Deleted rows are kept on disk as tombstones and skipped by the row iterators.
+    pub fn delete(&mut self, predicate: impl Fn(&T) -> bool) -> DbResult<usize> {
+        self.check_writable()?;
+        let current_page_offset = self.current_page_offset()?;
+
+        let mut dirty_pages = Vec::new();
+        for page in self.pages() {
+            let (offset, mut page) = page?;
+            let mut slots = Vec::new();
+            for row in decode_page(offset, &page, C::deserialize) {
+                let (row_offset, row) = row?;
+                if predicate(&row) {
+                    slots.push((row_offset - offset) as usize / ROW_SIZE);
+                }
+            }
+
+            if !slots.is_empty() {
+                for slot in &slots {
+                    page.delete(*slot);
+                }
+                dirty_pages.push((offset, slots, page));
+            }
+        }
+
+        self.page_cache.clear();
+        let mut deleted = 0;
+        for (offset, slots, page) in dirty_pages {
+            if offset == current_page_offset {
+                for slot in &slots {
+                    self.current_page.delete(*slot);
+                }
             }
-            _ => {}
+            self.write_page(offset, &page)?;
+            deleted += slots.len();
+        }
+
+        if deleted > 0 {
+            self.sync_if_needed()?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Rewrites the row stored at the given byte offset in place.
+    ///
+    /// The new row must still fit in a single slot and there must be a live row at the offset.
+    pub fn update_at(&mut self, offset: u64, row: T) -> DbResult<()> {
+        self.check_writable()?;
+        let row = C::serialize(&row)?;
+
+        let (page_offset, index) = Self::locate(offset)?;
+        let slot_offset = index * ROW_SIZE;
+
+        let mut buf = vec![0; self.format.stride()];
+        self.reader
+            .read_exact_at(&mut buf, self.format.physical_offset(page_offset))?;
+        let mut page = self.format.open(&buf)?;
+
+        page.update(index, &row)?;
+        self.page_cache.clear();
+
+        if page_offset == self.current_page_offset()? {
+            self.current_page.update(index, &row)?;
         }
 
-        if self.current_page.available_rows() == 0 {
-            self.current_page = Page::new();
+        // Encrypted pages can only be rewritten as a whole
+        if self.format.is_encrypted() {
+            self.write_page(page_offset, &page)?;
         } else {
-            self.writer.seek(io::SeekFrom::End(-(PAGE_SIZE as i64)))?;
+            self.writer.write_all_at(
+                &page.as_ref()[slot_offset..slot_offset + ROW_SIZE],
+                self.format.physical_offset(offset) + slot_offset as u64,
+            )?;
         }
+        self.sync_if_needed()?;
 
         Ok(())
     }
 
-    pub fn lock_writes(&mut self) -> DbResult<LockHandle> {
-        let fd = self.writer.as_raw_fd();
-        match unsafe { libc::flock(fd, libc::LOCK_EX) } {
-            0 => Ok(LockHandle { fd }),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "couldn't acquire lock").into()),
+    /// Maps the key of every live row to its offset, so rows can be looked up with [`Db::get`].
+    /// When several rows share a key the newest one wins. Keep it up to date with
+    /// [`Db::insert_indexed`].
+    pub fn build_index<K: Eq + Hash>(
+        &mut self,
+        key_fn: impl Fn(&T) -> K,
+    ) -> DbResult<HashMap<K, u64>> {
+        self.rows_with_offset()
+            .map(|row| row.map(|(offset, row)| (key_fn(&row), offset)))
+            .collect()
+    }
+
+    /// Same as [`Db::insert`], also adding the row to an index built by [`Db::build_index`].
+    pub fn insert_indexed<K: Eq + Hash>(
+        &mut self,
+        row: T,
+        index: &mut HashMap<K, u64>,
+        key_fn: impl Fn(&T) -> K,
+    ) -> DbResult<u64> {
+        let key = key_fn(&row);
+        let offset = self.insert(row)?;
+        index.insert(key, offset);
+        Ok(offset)
+    }
+
+    /// Writes every live row to `out` as a CSV record, reading one page at a time. Rows that
+    /// serialize as structs get a header record with their field names.
+    #[cfg(feature = "csv")]
+    pub fn export_csv<W: Write>(&mut self, out: W) -> DbResult<()> {
+        let mut writer = csv::Writer::from_writer(out);
+        for row in self.rows() {
+            writer
+                .serialize(row?)
+                .map_err(|err| Error::Serialization(Box::new(err)))?;
         }
+        writer.flush()?;
+        Ok(())
     }
 
-    fn pages(&mut self) -> impl Iterator<Item = Page<ROW_SIZE>> + '_ {
-        let mut cursor = 0;
-        iter::from_fn(move || {
-            let offset = (cursor * PAGE_SIZE) as u64;
+    /// Reads the row stored at the given byte offset, like the ones returned by [`Db::insert`],
+    /// without scanning the pages before it. Returns `None` when there's no live row there,
+    /// including offsets past the end of the file.
+    pub fn get(&mut self, offset: u64) -> DbResult<Option<T>> {
+        let (page_offset, index) = Self::locate(offset)?;
+
+        let (format, source, cache) = self.scan();
+        let mut buf = vec![0; format.stride()];
+        let Some(page) = read_page(
+            format,
+            &source,
+            cache,
+            format.physical_offset(page_offset),
+            &mut buf,
+        ) else {
+            return Ok(None);
+        };
+
+        let page = page?;
+        match page.get(index) {
+            Some(_) if !page.is_intact(index) => Err(Error::Corrupt { offset }),
+            Some(row) => Ok(Some(C::deserialize(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds the row with the given key in a database whose rows were inserted in the order of that
+    /// key, like timestamps, binary searching the pages and then the rows of one. Only the probed
+    /// rows are deserialized. Returns the row along with its offset, or `None` when no live row
+    /// has the key.
+    pub fn search_by<K: Ord>(
+        &mut self,
+        key: K,
+        extract: impl Fn(&T) -> K,
+    ) -> DbResult<Option<(u64, T)>> {
+        let len = self.reader.metadata()?.len();
+        let (format, source, cache) = self.scan();
+        let mut buf = vec![0; format.stride()];
+
+        // Rows that don't deserialize sort before every key
+        let key = Some(key);
+        let key_of = |row: &[u8]| C::deserialize(row).ok().map(|row| extract(&row));
+
+        let (mut low, mut high) = (0, format.page_count(len) as usize);
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            // Pages without live rows are skipped, probing the ones after them
+            let mut probe = mid;
+            let page = loop {
+                if probe == high {
+                    break None;
+                }
+                let offset = format.physical_offset((probe * PAGE_SIZE) as u64);
+                match read_page(format, &source, cache, offset, &mut buf) {
+                    Some(page) => {
+                        let page = page?;
+                        if page.rows().next().is_some() {
+                            break Some(page);
+                        }
+                    }
+                    None => break None,
+                }
+                probe += 1;
+            };
+            let Some(page) = page else {
+                high = mid;
+                continue;
+            };
 
-            if self.reader.seek(io::SeekFrom::Start(offset)).is_err() {
-                return None;
+            let (Some(first), Some(last)) = (page.rows().next(), page.rows().last()) else {
+                unreachable!("only pages with live rows are probed");
+            };
+            if key < key_of(first) {
+                high = mid;
+            } else if key > key_of(last) {
+                low = probe + 1;
+            } else {
+                let Ok(slot) = page.search_by(&key, key_of) else {
+                    return Ok(None);
+                };
+                let offset = (probe * PAGE_SIZE + slot * ROW_SIZE) as u64;
+                return match page.get(slot) {
+                    Some(_) if !page.is_intact(slot) => Err(Error::Corrupt { offset }),
+                    Some(row) => Ok(Some((offset, C::deserialize(row)?))),
+                    None => Ok(None),
+                };
             }
+        }
 
-            let mut buf = vec![0; PAGE_SIZE];
-            cursor += 1;
-            match self.reader.read_exact(&mut buf) {
-                Ok(()) => Some(Page::from_bytes(buf)),
-                Err(_) => None,
+        Ok(None)
+    }
+
+    /// Splits a row offset into the offset of its page and its slot in the page.
+    fn locate(offset: u64) -> DbResult<(u64, usize)> {
+        let page_offset = offset - offset % PAGE_SIZE as u64;
+        let slot_offset = (offset - page_offset) as usize;
+        if !slot_offset.is_multiple_of(ROW_SIZE) || slot_offset + ROW_SIZE > PAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset is not aligned to a row",
+            )
+            .into());
+        }
+        Ok((page_offset, slot_offset / ROW_SIZE))
+    }
+
+    /// Removes every row, truncating the file back to its header.
+    pub fn clear(&mut self) -> DbResult<()> {
+        self.check_writable()?;
+
+        let header_size = self.format.header_size();
+        self.writer.set_len(header_size)?;
+        self.writer.seek(io::SeekFrom::Start(header_size))?;
+        self.current_page = self.format.new_page();
+        self.page_cache.clear();
+        self.sync_if_needed()
+    }
+
+    /// Makes sure every write reached the file.
+    pub fn flush(&mut self) -> DbResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Flushes and syncs the file to disk, regardless of the `sync_writes` setting.
+    pub fn sync(&mut self) -> DbResult<()> {
+        self.writer.flush()?;
+        self.writer.sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Copies every complete page to a new file at `dest`, which can be opened as a database of
+    /// its own. Writes from other handles are held off by the write lock while copying, so don't
+    /// call it while holding [`Db::lock_writes`] from this handle, as its lock is released here.
+    pub fn backup(&mut self, dest: impl AsRef<Path>) -> DbResult<()> {
+        let _lock = self.lock_writes()?;
+        self.flush()?;
+
+        let end = self
+            .format
+            .last_page_boundary(self.reader.metadata()?.len());
+        self.reader.seek(io::SeekFrom::Start(0))?;
+
+        let mut dest = File::create(dest)?;
+        io::copy(&mut (&self.reader).take(end), &mut dest)?;
+        dest.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Rewrites the live rows packed into a new file, leaving out deleted and expired rows and
+    /// preallocated space, and renames it over the database. Rows get new offsets, and other handles keep
+    /// reading the old file until reopened. Like [`Db::backup`], don't call it while holding
+    /// [`Db::lock_writes`] from this handle.
+    pub fn compact(&mut self) -> DbResult<()> {
+        self.check_writable()?;
+        let lock = self.lock_writes()?;
+        self.flush()?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".compact");
+        let file = File::create(&tmp)?;
+        let mut out = io::BufWriter::new(&file);
+        out.write_all(&self.format.header::<ROW_SIZE>())?;
+
+        let format = self.format.clone();
+        let expires = self.expiry.is_some();
+        let live = self.live_rows();
+        let mut packed = format.new_page::<ROW_SIZE>();
+        for page in self.pages() {
+            let (offset, mut page) = page?;
+            // Going backwards, so removing a row doesn't move the ones left to check
+            let slots = page.entries().map(|(slot, _)| slot).collect::<Vec<_>>();
+            for slot in slots.into_iter().rev() {
+                let offset = offset + (slot * ROW_SIZE) as u64;
+                if !page.is_intact(slot) {
+                    return Err(Error::Corrupt { offset });
+                }
+                let expired = expires
+                    && page
+                        .get(slot)
+                        .is_some_and(|row| !live(&C::deserialize(row).map(|row| (offset, row))));
+                if expired {
+                    page.remove(slot);
+                }
             }
-        })
+
+            for row in page.rows() {
+                packed.insert(row)?;
+                if packed.is_full() {
+                    out.write_all(&format.seal(&packed))?;
+                    packed = format.new_page();
+                }
+            }
+        }
+        if !packed.is_unused() {
+            out.write_all(&format.seal(&packed))?;
+        }
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        // The lock is on the old file, so it has to go before its handles are closed
+        drop(lock);
+        self.reader = File::open(&self.path)?;
+        #[cfg(feature = "mmap")]
+        {
+            self.map = None;
+        }
+        self.writer = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.current_page = last_page(&mut self.writer, &self.format)?;
+        self.page_cache.clear();
+
+        Ok(())
     }
 
-    fn pages_reverse(&mut self) -> impl Iterator<Item = Page<ROW_SIZE>> + '_ {
-        let mut cursor = 1;
-        iter::from_fn(move || {
-            let offset = (cursor * PAGE_SIZE) as i64;
+    /// Reads the oldest live row.
+    pub fn first(&mut self) -> DbResult<Option<T>> {
+        self.rows().next().transpose()
+    }
+
+    /// Reads the newest live row, straight from the page being filled when it has any. Rows
+    /// written by other handles are only seen once [`Db::lock_writes`] reloads that page.
+    pub fn last(&mut self) -> DbResult<Option<T>> {
+        let live = self.live_rows();
+        let offset = self.current_page_offset()?;
+        let rows = decode_page(offset, &self.current_page, C::deserialize);
+        if let Some(row) = rows.into_iter().filter(live).last() {
+            return row.map(|(_, row)| Some(row));
+        }
+
+        self.rows_reverse().next().transpose()
+    }
+
+    /// Checks every page, returning the offsets of the ones that can't be read back, either
+    /// because they fail the integrity checks or because a row doesn't deserialize.
+    pub fn verify(&mut self) -> DbResult<Vec<u64>> {
+        Ok(self
+            .pages()
+            .enumerate()
+            .filter_map(|(index, page)| {
+                let offset = (index * PAGE_SIZE) as u64;
+                let intact = page.is_ok_and(|(_, page)| {
+                    decode_page(offset, &page, C::deserialize::<T>)
+                        .iter()
+                        .all(Result::is_ok)
+                });
+                (!intact).then_some(offset)
+            })
+            .collect())
+    }
+
+    /// Counts the live rows in the database without deserializing them.
+    pub fn count(&mut self) -> DbResult<u64> {
+        self.pages()
+            .map(|page| page.map(|(_, page)| page.row_count() as u64))
+            .sum()
+    }
+
+    /// Size of the file on disk, including the header and any preallocated space.
+    pub fn file_len(&self) -> io::Result<u64> {
+        Ok(self.reader.metadata()?.len())
+    }
+
+    /// Number of pages in the file, from its size alone. Preallocated pages count too.
+    pub fn page_count(&self) -> io::Result<u64> {
+        Ok(self.format.page_count(self.file_len()?))
+    }
+
+    /// Offset of the page currently being filled by inserts.
+    fn current_page_offset(&mut self) -> io::Result<u64> {
+        Ok(self.format.logical_offset(self.writer.stream_position()?))
+    }
+
+    /// Writes a whole page back to its place in the file, without moving the writer.
+    fn write_page(&self, offset: u64, page: &Page<ROW_SIZE, PAGE_SIZE>) -> io::Result<()> {
+        self.writer
+            .write_all_at(&self.format.seal(page), self.format.physical_offset(offset))
+    }
+
+    fn check_writable(&self) -> DbResult<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn sync_if_needed(&mut self) -> DbResult<()> {
+        match self.sync_writes {
+            Some(interval) if self.last_sync.elapsed() > interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Blocks until no other handle holds the write lock of the file. The last page is reloaded
+    /// once the lock is acquired, as other handles may have written to the file meanwhile.
+    pub fn lock_writes(&mut self) -> DbResult<LockHandle> {
+        let lock = lock::lock(lock::raw_file(&self.writer), LockMode::Exclusive)?;
+
+        self.current_page = last_page(&mut self.writer, &self.format)?;
+        self.page_cache.clear();
+
+        Ok(lock)
+    }
 
-            if self.reader.seek(io::SeekFrom::End(-offset)).is_err() {
-                return None;
+    /// Blocks until no other handle holds the write lock, then shares the lock with other
+    /// readers, keeping writers out until the handle is dropped.
+    ///
+    /// To write, upgrade with [`Db::lock_writes`] on the same `Db`. The upgrade isn't atomic, so
+    /// rows read under the shared lock may be stale by then, and dropping either handle releases
+    /// the lock. Windows can't upgrade locks at all, so drop the shared lock first there.
+    pub fn lock_reads(&mut self) -> DbResult<LockHandle> {
+        Ok(lock::lock(lock::raw_file(&self.writer), LockMode::Shared)?)
+    }
+
+    /// Same as [`Db::lock_writes`], but returns `None` instead of blocking when another handle
+    /// holds the lock.
+    pub fn try_lock_writes(&mut self) -> DbResult<Option<LockHandle>> {
+        let Some(lock) = lock::try_lock(lock::raw_file(&self.writer), LockMode::Exclusive)? else {
+            return Ok(None);
+        };
+
+        self.current_page = last_page(&mut self.writer, &self.format)?;
+        self.page_cache.clear();
+
+        Ok(Some(lock))
+    }
+
+    /// Same as [`Db::lock_writes`], but gives up with [`Error::WouldBlock`] after the timeout.
+    pub fn lock_writes_timeout(&mut self, timeout: Duration) -> DbResult<LockHandle> {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            if let Some(lock) = self.try_lock_writes()? {
+                return Ok(lock);
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(Error::WouldBlock);
             }
 
-            let mut buf = vec![0; PAGE_SIZE];
+            thread::sleep(backoff.min(timeout - elapsed));
+            backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+        }
+    }
+
+    fn pages(&mut self) -> impl Iterator<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> + '_ {
+        self.pages_from(0)
+    }
+
+    fn pages_from(
+        &mut self,
+        first_page: usize,
+    ) -> impl Iterator<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> + '_ {
+        let (format, source, cache) = self.scan();
+        let stride = format.stride();
+        let mut cursor = first_page;
+        let mut buf = vec![0; stride];
+        iter::from_fn(move || {
+            let offset = format.physical_offset((cursor * PAGE_SIZE) as u64);
+            let page = read_page(format, &source, cache, offset, &mut buf)?;
             cursor += 1;
-            match self.reader.read_exact(&mut buf) {
-                Ok(()) => Some(Page::from_bytes(buf)),
-                Err(_) => None,
+            match page {
+                // Only preallocated space is left past the first unused page
+                Ok(page) if page.is_unused() => None,
+                page => Some(
+                    page.map(|page| (format.logical_offset(offset), page))
+                        .map_err(Error::from),
+                ),
             }
         })
     }
 
-    pub fn rows(&mut self) -> impl Iterator<Item = DbResult<T>> + '_ {
-        self.pages().flat_map(|page| {
-            page.rows()
-                .map(|row| bitcode::deserialize(row).map_err(|err| err.into()))
-                .collect::<Vec<_>>()
+    fn pages_reverse(
+        &mut self,
+    ) -> impl Iterator<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> + '_ {
+        let mut end = self
+            .reader
+            .metadata()
+            .map(|metadata| self.format.last_page_boundary(metadata.len()))
+            .unwrap_or(0);
+        let (format, source, cache) = self.scan();
+        let mut buf = vec![0; format.stride()];
+        iter::from_fn(move || {
+            let offset = format.previous_page(end)?;
+            let page = read_page(format, &source, cache, offset, &mut buf)?;
+            end = offset;
+            Some(
+                page.map(|page| (format.logical_offset(offset), page))
+                    .map_err(Error::from),
+            )
         })
+        .skip_while(|page| matches!(page, Ok((_, page)) if page.is_unused()))
+    }
+
+    /// Where page scans read from, along with the cache they go through first. The file is mapped
+    /// once and again only when its size changes, falling back to regular reads when it can't be.
+    #[allow(clippy::type_complexity)]
+    fn scan(
+        &mut self,
+    ) -> (
+        &PageFormat<PAGE_SIZE>,
+        PageSource<'_>,
+        &mut PageCache<ROW_SIZE, PAGE_SIZE>,
+    ) {
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            let len = self.reader.metadata().map(|metadata| metadata.len()).ok();
+            if self.map.as_ref().map(|map| map.len() as u64) != len {
+                self.map = unsafe { memmap2::Mmap::map(&self.reader) }
+                    .ok()
+                    .map(Arc::new);
+            }
+        }
+
+        let source = PageSource::File(&self.reader);
+        #[cfg(feature = "mmap")]
+        let source = match &self.map {
+            Some(map) if self.mmap => PageSource::Map(map),
+            _ => source,
+        };
+
+        (&self.format, source, &mut self.page_cache)
+    }
+
+    pub fn rows(&mut self) -> impl Iterator<Item = DbResult<T>> + '_ {
+        self.rows_with_offset().map(|row| row.map(|(_, row)| row))
     }
 
     pub fn rows_reverse(&mut self) -> impl Iterator<Item = DbResult<T>> + '_ {
-        self.pages_reverse().flat_map(|page| {
-            page.rows()
-                .map(|row| bitcode::deserialize(row).map_err(|err| err.into()))
-                .collect::<Vec<_>>()
-                .into_iter()
-                .rev()
+        self.rows_reverse_with_offset()
+            .map(|row| row.map(|(_, row)| row))
+    }
+
+    /// Same as [`Db::rows`], but also yields the absolute byte offset of each row in the file.
+    pub fn rows_with_offset(&mut self) -> impl Iterator<Item = DbResult<(u64, T)>> + '_ {
+        let live = self.live_rows();
+        self.pages()
+            .flat_map(|page| match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize),
+                Err(err) => vec![Err(err)],
+            })
+            .filter(live)
+    }
+
+    /// Same as [`Db::rows_with_offset`], but yielding rows as they're stored, without
+    /// deserializing them, so callers can decode just what they need. The rows of a page share its
+    /// buffer instead of being copied. Expired rows aren't filtered out, as that takes decoding.
+    pub fn rows_raw(&mut self) -> impl Iterator<Item = DbResult<RawRow<ROW_SIZE, PAGE_SIZE>>> + '_ {
+        self.pages().flat_map(|page| match page {
+            Ok((offset, page)) => {
+                let page = Rc::new(page);
+                page.entries()
+                    .map(|(slot, _)| {
+                        let offset = offset + (slot * ROW_SIZE) as u64;
+                        if !page.is_intact(slot) {
+                            return Err(Error::Corrupt { offset });
+                        }
+                        Ok(RawRow {
+                            offset,
+                            page: page.clone(),
+                            slot,
+                        })
+                    })
+                    .collect()
+            }
+            Err(err) => vec![Err(err)],
         })
     }
+
+    /// Same as [`Db::rows`], but starting at the given row, without reading the pages before it.
+    ///
+    /// Rows are counted by slot, so deleted rows still count towards `skip_rows`.
+    pub fn rows_from(&mut self, skip_rows: usize) -> impl Iterator<Item = DbResult<T>> + '_ {
+        let rows_per_page = PAGE_SIZE / ROW_SIZE;
+        let first_page = skip_rows / rows_per_page;
+        let start = (first_page * PAGE_SIZE + skip_rows % rows_per_page * ROW_SIZE) as u64;
+        let live = self.live_rows();
+
+        self.pages_from(first_page)
+            .flat_map(|page| match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize),
+                Err(err) => vec![Err(err)],
+            })
+            .filter(move |row| !matches!(row, Ok((offset, _)) if *offset < start))
+            .filter(live)
+            .map(|row| row.map(|(_, row)| row))
+    }
+
+    /// Same as [`Db::rows`], but only the rows whose offsets fall in `start..end`, reading just the
+    /// pages in between. Bounds within a slot are rounded up to the next one.
+    pub fn range(&mut self, start: u64, end: u64) -> impl Iterator<Item = DbResult<T>> + '_ {
+        let first_page = (start / PAGE_SIZE as u64) as usize;
+        let live = self.live_rows();
+
+        self.pages_from(first_page)
+            .take_while(move |page| !matches!(page, Ok((offset, _)) if *offset >= end))
+            .flat_map(|page| match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize),
+                Err(err) => vec![Err(err)],
+            })
+            .filter(move |row| !matches!(row, Ok((offset, _)) if *offset < start || *offset >= end))
+            .filter(live)
+            .map(|row| row.map(|(_, row)| row))
+    }
+
+    /// Same as [`Db::rows_reverse`], but also yields the absolute byte offset of each row in the
+    /// file.
+    pub fn rows_reverse_with_offset(&mut self) -> impl Iterator<Item = DbResult<(u64, T)>> + '_ {
+        let live = self.live_rows();
+        self.pages_reverse()
+            .flat_map(|page| match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize)
+                    .into_iter()
+                    .rev()
+                    .collect(),
+                Err(err) => vec![Err(err)],
+            })
+            .filter(live)
+    }
+
+    /// Hides rows from the row iterators once they're older than `ttl`, going by the time
+    /// `created_at` reads from them. Expired rows stay in the file until [`Db::compact`], and
+    /// still count towards [`Db::count`] and [`Db::get`] until then.
+    pub fn expire_after(
+        &mut self,
+        ttl: Duration,
+        created_at: impl Fn(&T) -> SystemTime + Send + Sync + 'static,
+    ) {
+        self.expiry = Some(Expiry {
+            ttl,
+            created_at: Arc::new(created_at),
+        });
+    }
+
+    /// Filter keeping the rows that haven't expired yet, checked lazily as rows are read.
+    fn live_rows(&self) -> impl Fn(&DbResult<(u64, T)>) -> bool {
+        let expiry = self.expiry.as_ref().and_then(|expiry| {
+            let cutoff = SystemTime::now().checked_sub(expiry.ttl)?;
+            Some((cutoff, expiry.created_at.clone()))
+        });
+
+        move |row| match (&expiry, row) {
+            (Some((cutoff, created_at)), Ok((_, row))) => created_at(row) >= *cutoff,
+            _ => true,
+        }
+    }
+}
+
+/// A row as stored in its slot, yielded by [`Db::rows_raw`]. Derefs to the serialized row.
+pub struct RawRow<const ROW_SIZE: usize, const PAGE_SIZE: usize> {
+    offset: u64,
+    page: Rc<Page<ROW_SIZE, PAGE_SIZE>>,
+    slot: usize,
+}
+
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize> RawRow<ROW_SIZE, PAGE_SIZE> {
+    /// Absolute byte offset of the row in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize> Deref for RawRow<ROW_SIZE, PAGE_SIZE> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.page.get(self.slot).unwrap_or_default()
+    }
+}
+
+/// Reads the last page of the file, positioning it to be rewritten when the page still has room.
+/// Otherwise the file is positioned at its end and a new page is returned.
+fn last_page<const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+    file: &mut File,
+    format: &PageFormat<PAGE_SIZE>,
+) -> io::Result<Page<ROW_SIZE, PAGE_SIZE>> {
+    // A crash in the middle of a write may leave a trailing partial page behind, which is ignored
+    // and overwritten by the next insert
+    let mut end = format.last_page_boundary(file.metadata()?.len());
+
+    while let Some(offset) = format.previous_page(end) {
+        let mut buf = vec![0; format.stride()];
+        file.read_exact_at(&mut buf, offset)?;
+
+        // Preallocated pages are filled before growing the file
+        let page = format.open(&buf)?;
+        if page.is_unused() {
+            end = offset;
+            continue;
+        }
+
+        if !page.is_full() {
+            file.seek(io::SeekFrom::Start(offset))?;
+            return Ok(page);
+        }
+        break;
+    }
+
+    file.seek(io::SeekFrom::Start(end))?;
+    Ok(format.new_page())
+}
+
+/// Opens the page at the given physical offset, taking it from the cache when it's there. Returns
+/// `None` past the end of the file.
+fn read_page<const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+    format: &PageFormat<PAGE_SIZE>,
+    source: &PageSource,
+    cache: &mut PageCache<ROW_SIZE, PAGE_SIZE>,
+    offset: u64,
+    buf: &mut [u8],
+) -> Option<io::Result<Page<ROW_SIZE, PAGE_SIZE>>> {
+    let logical_offset = format.logical_offset(offset);
+    if let Some(page) = cache.get(logical_offset) {
+        return Some(Ok(page));
+    }
+
+    let page = source.open(format, offset, buf)?;
+    if let Ok(page) = &page {
+        if !page.is_unused() {
+            cache.insert(logical_offset, page);
+        }
+    }
+    Some(page)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Pages read from the file by each thread, so tests can tell what the page cache saves.
+    static PAGE_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+enum PageSource<'a> {
+    File(&'a File),
+    #[cfg(feature = "mmap")]
+    Map(&'a Arc<memmap2::Mmap>),
+}
+
+impl PageSource<'_> {
+    /// Opens the page at `offset`, reading it into the buffer, or `None` past the end of the file.
+    /// Pages of mapped files are read from the map directly, leaving the buffer untouched.
+    fn open<const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+        &self,
+        format: &PageFormat<PAGE_SIZE>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Option<io::Result<Page<ROW_SIZE, PAGE_SIZE>>> {
+        #[cfg(test)]
+        PAGE_READS.with(|reads| reads.set(reads.get() + 1));
+
+        match self {
+            Self::File(file) => {
+                file.read_exact_at(buf, offset).ok()?;
+                Some(format.open(buf))
+            }
+            #[cfg(feature = "mmap")]
+            Self::Map(map) => {
+                let offset = usize::try_from(offset).ok()?;
+                map.get(offset..offset + buf.len())?;
+                Some(format.open_mapped(map, offset))
+            }
+        }
+    }
+}
+
+/// Deserializes the live rows of a page starting at the given byte offset, pairing each row with
+/// its own offset. Rows failing the integrity check yield [`Error::Corrupt`].
+pub(crate) fn decode_page<T, const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+    offset: u64,
+    page: &Page<ROW_SIZE, PAGE_SIZE>,
+    decode: impl Fn(&[u8]) -> DbResult<T>,
+) -> Vec<DbResult<(u64, T)>> {
+    page.entries()
+        .map(|(slot, row)| {
+            let offset = offset + (slot * ROW_SIZE) as u64;
+            if !page.is_intact(slot) {
+                return Err(Error::Corrupt { offset });
+            }
+            Ok((offset, decode(row)?))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -190,6 +1056,7 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::page::SizePrefix;
 
     #[test]
     fn test_db_rows() {
@@ -220,4 +1087,1098 @@ mod tests {
         let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
         assert_eq!(vec![5, 4, 3, 2, 1], rows);
     }
+
+    #[test]
+    fn test_db_delete() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+        db.insert(3).unwrap();
+        db.insert(4).unwrap();
+        db.insert(5).unwrap();
+
+        assert_eq!(1, db.delete(|row| *row == 3).unwrap());
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 4, 5], rows);
+
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![5, 4, 2, 1], rows);
+
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 4, 5], rows);
+    }
+
+    #[test]
+    fn test_db_update_at() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+        db.insert(3).unwrap();
+        db.insert(4).unwrap();
+        db.insert(5).unwrap();
+
+        // Second row of the first page, which is no longer the current one
+        db.update_at(2048, 20).unwrap();
+        // First row of the current page
+        db.update_at(2 * DEFAULT_PAGE_SIZE as u64, 50).unwrap();
+        db.insert(6).unwrap();
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 20, 3, 4, 50, 6], rows);
+
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 20, 3, 4, 50, 6], rows);
+    }
+
+    #[test]
+    fn test_db_update_at_rejects_invalid_rows() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<String, 32>::from_path(tmp.path().join("test.espora")).unwrap();
+
+        db.insert(String::from("Rinha")).unwrap();
+        db.insert(String::from("de")).unwrap();
+
+        assert!(db.update_at(0, "Backend".repeat(10)).is_err());
+        assert!(db.update_at(10, String::from("Backend")).is_err());
+        assert!(matches!(
+            db.update_at(64, String::from("Backend")),
+            Err(Error::NotFound)
+        ));
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![String::from("Rinha"), String::from("de")], rows);
+    }
+
+    #[test]
+    fn test_db_rows_with_offset() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+        db.insert(3).unwrap();
+        db.insert(4).unwrap();
+        db.insert(5).unwrap();
+
+        let rows = db.rows_with_offset().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            vec![(0, 1), (2048, 2), (4096, 3), (6144, 4), (8192, 5)],
+            rows
+        );
+
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+        let rows = db
+            .rows_reverse_with_offset()
+            .collect::<DbResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            vec![(8192, 5), (6144, 4), (4096, 3), (2048, 2), (0, 1)],
+            rows
+        );
+
+        let (offset, _) = rows[3];
+        let file = File::open(&path).unwrap();
+        let mut slot = [0; 2048];
+        file.read_exact_at(&mut slot, DEFAULT_PAGE_SIZE as u64 + offset)
+            .unwrap();
+        let page = Page::<2048>::from_bytes(slot.to_vec());
+        let row = page.rows().next().unwrap();
+        assert_eq!(2, bitcode::deserialize::<i64>(row).unwrap());
+    }
+
+    #[test]
+    fn test_db_count() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 1024>::from_path(tmp.path().join("test.espora")).unwrap();
+        assert_eq!(0, db.count().unwrap());
+
+        for i in 0..10 {
+            db.insert(i).unwrap();
+        }
+        db.delete(|row| row % 3 == 0).unwrap();
+
+        assert_eq!(6, db.count().unwrap());
+        assert_eq!(db.rows().count() as u64, db.count().unwrap());
+    }
+
+    #[test]
+    fn test_db_custom_page_size() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 128, 512>::from_path(&path).unwrap();
+
+        for i in 0..10 {
+            db.insert(i).unwrap();
+        }
+
+        let mut db = Db::<i64, 128, 512>::from_path(&path).unwrap();
+        db.insert(10).unwrap();
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!((0..=10).collect::<Vec<_>>(), rows);
+        assert_eq!(4 * 512, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn test_db_insert_oversized_row() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<String, 32>::from_path(&path).unwrap();
+
+        db.insert(String::from("Rinha")).unwrap();
+        assert!(matches!(
+            db.insert("Rinha de Backend".repeat(2)),
+            Err(Error::RowTooLarge { .. })
+        ));
+        db.insert(String::from("2024")).unwrap();
+
+        let mut db = Db::<String, 32>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![String::from("Rinha"), String::from("2024")], rows);
+    }
+
+    #[test]
+    fn test_db_corrupted_row() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+        db.insert(3).unwrap();
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        // A varint size prefix of 4096 bytes
+        file.write_all_at(&[0x80, 0x40], 4096 + 2048).unwrap();
+
+        let rows = db.rows().collect::<Vec<_>>();
+        assert_eq!(3, rows.len());
+        assert!(matches!(rows[0], Ok(1)));
+        assert!(matches!(rows[1], Err(Error::Corrupt { offset: 2048 })));
+        assert!(matches!(rows[2], Ok(3)));
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn test_db_checksum_mismatch() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xff], 4096 + 2048 + 5).unwrap();
+
+        let rows = db.rows().collect::<Vec<_>>();
+        assert!(matches!(rows[0], Ok(1)));
+        assert!(matches!(rows[1], Err(Error::Corrupt { offset: 2048 })));
+    }
+
+    #[test]
+    fn test_db_headerless_file() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        // Laid out as before there were headers: pages right from the start of the file, with a
+        // fixed size prefix and the payload in each slot
+        let mut file = Vec::new();
+        for row in [1_i64, 2, 3] {
+            let payload = bitcode::serialize(&row).unwrap();
+            let mut slot = (payload.len() as u64).to_be_bytes().to_vec();
+            slot.extend_from_slice(&payload);
+            slot.resize(2048, 0);
+            file.extend_from_slice(&slot);
+        }
+        file.resize(2 * DEFAULT_PAGE_SIZE, 0);
+        std::fs::write(&path, file).unwrap();
+
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 3], rows);
+
+        db.insert(4).unwrap();
+        db.delete(|row| *row == 1).unwrap();
+
+        let mut db = Db::<i64, 2048>::from_path(&path).unwrap();
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![4, 3, 2], rows);
+        assert_eq!(
+            2 * DEFAULT_PAGE_SIZE as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_db_checksums_from_header() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        // Written as if by a build with the opposite `crc` feature
+        let checksums = !cfg!(feature = "crc");
+        let format = PageFormat::<DEFAULT_PAGE_SIZE> {
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            layout: RowLayout {
+                checksums,
+                ..RowLayout::default()
+            },
+            headerless: false,
+        };
+        let mut page = format.new_page::<64>();
+        page.insert(&bitcode::serialize(&1_i64).unwrap()).unwrap();
+        let mut file = format.header::<64>();
+        file.extend_from_slice(&format.seal(&page));
+        std::fs::write(&path, file).unwrap();
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert(2).unwrap();
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2], rows);
+
+        // The checksum of the new row follows its one byte size prefix
+        let mut slot = [0; 64];
+        File::open(&path)
+            .unwrap()
+            .read_exact_at(&mut slot, 4096 + 64)
+            .unwrap();
+        let payload = bitcode::serialize(&2_i64).unwrap();
+        let checksum = crc32fast::hash(&payload).to_be_bytes();
+        assert_eq!(checksums, slot[1..5] == checksum);
+    }
+
+    #[test]
+    fn test_db_reopen_fills_last_page() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        db.insert(3).unwrap();
+        db.insert(4).unwrap();
+        assert_eq!(
+            2 * DEFAULT_PAGE_SIZE as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        db.insert(5).unwrap();
+        assert_eq!(
+            3 * DEFAULT_PAGE_SIZE as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], rows);
+    }
+
+    #[test]
+    fn test_db_ignores_trailing_partial_page() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+
+        for i in 1..=5 {
+            db.insert(i).unwrap();
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3, 4, 5]).unwrap();
+
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![5, 4, 3, 2, 1], rows);
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        db.insert(6).unwrap();
+        db.insert(7).unwrap();
+
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![7, 6, 5, 4, 3, 2, 1], rows);
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], rows);
+    }
+
+    /// Bitcode with the bytes reversed, just to have a second encoding around.
+    struct ReversedBitcode;
+
+    impl Codec for ReversedBitcode {
+        fn serialize<T: Serialize>(row: &T) -> Result<Vec<u8>, Error> {
+            let mut bytes = Bitcode::serialize(row)?;
+            bytes.reverse();
+            Ok(bytes)
+        }
+
+        fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+            let mut bytes = bytes.to_vec();
+            bytes.reverse();
+            Bitcode::deserialize(&bytes)
+        }
+    }
+
+    #[test]
+    fn test_db_codecs() {
+        let tmp = tempdir().unwrap();
+        let rows = vec![
+            (1, String::from("Rinha")),
+            (2, String::from("de")),
+            (3, String::from("Backend")),
+        ];
+
+        let bitcode_path = tmp.path().join("bitcode.espora");
+        let mut db = Db::<(i64, String), 64, 4096, Bitcode>::from_path(&bitcode_path).unwrap();
+        for row in rows.clone() {
+            db.insert(row).unwrap();
+        }
+        assert_eq!(rows, db.rows().collect::<DbResult<Vec<_>>>().unwrap());
+
+        let reversed_path = tmp.path().join("reversed.espora");
+        let mut db =
+            Db::<(i64, String), 64, 4096, ReversedBitcode>::from_path(&reversed_path).unwrap();
+        for row in rows.clone() {
+            db.insert(row).unwrap();
+        }
+        assert_eq!(rows, db.rows().collect::<DbResult<Vec<_>>>().unwrap());
+
+        assert_ne!(
+            std::fs::read(bitcode_path).unwrap(),
+            std::fs::read(reversed_path).unwrap()
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_db_encryption() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<String, 64>::builder()
+            .encrypt([42; 32])
+            .build::<String, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+        for i in 0..100 {
+            db.insert(format!("Rinha {i}")).unwrap();
+        }
+        db.update_at(64, String::from("Backend")).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("Rinha"));
+
+        let mut db = Db::<String, 64>::builder()
+            .encrypt([42; 32])
+            .build::<String, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+        db.insert(String::from("2024")).unwrap();
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(101, rows.len());
+        assert_eq!("Rinha 0", rows[0]);
+        assert_eq!("Backend", rows[1]);
+        assert_eq!("2024", rows[100]);
+
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!("2024", rows[0]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_db_encryption_wrong_key() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::builder()
+            .encrypt([42; 32])
+            .build::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+        db.insert(1).unwrap();
+
+        assert!(Db::<i64, 64>::builder()
+            .encrypt([7; 32])
+            .build::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_db_mmap_scan() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<(i64, String), 64>::from_path(&path).unwrap();
+        for i in 0..1000 {
+            db.insert((i, format!("Rinha {i}"))).unwrap();
+        }
+
+        let mut mapped = Db::<(i64, String), 64>::builder()
+            .mmap(true)
+            .build::<(i64, String), 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+
+        let rows = db.rows_with_offset().collect::<DbResult<Vec<_>>>().unwrap();
+        let mapped_rows = mapped
+            .rows_with_offset()
+            .collect::<DbResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(1000, rows.len());
+        assert_eq!(rows, mapped_rows);
+
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        let mapped_rows = mapped.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(rows, mapped_rows);
+
+        // The file is only mapped again once it grows
+        let map = mapped.map.clone().unwrap();
+        assert_eq!(1000, mapped.rows().count());
+        assert!(Arc::ptr_eq(&map, mapped.map.as_ref().unwrap()));
+
+        for i in 1000..1100 {
+            db.insert((i, format!("Rinha {i}"))).unwrap();
+        }
+        assert_eq!(1100, mapped.rows().count());
+        assert!(!Arc::ptr_eq(&map, mapped.map.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_db_insert_many() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert(-1).unwrap();
+        db.insert_many(0..1000).unwrap();
+        db.insert(1000).unwrap();
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!((-1..=1000).collect::<Vec<_>>(), rows);
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert_many(vec![1001, 1002]).unwrap();
+        assert_eq!(1004, db.count().unwrap());
+        assert_eq!(Some(1002), db.rows_reverse().next().transpose().unwrap());
+    }
+
+    #[test]
+    fn test_db_sync() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::builder()
+            .sync_writes(false)
+            .build::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+        db.insert_many(0..100).unwrap();
+        db.flush().unwrap();
+        db.sync().unwrap();
+        drop(db);
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), rows);
+    }
+
+    #[test]
+    fn test_db_clear() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert_many(0..100).unwrap();
+        db.clear().unwrap();
+
+        assert_eq!(0, db.rows().count());
+        assert_eq!(
+            DEFAULT_PAGE_SIZE as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        db.insert(1).unwrap();
+        db.insert(2).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2], rows);
+    }
+
+    #[test]
+    fn test_db_rows_from() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 256, 1024>::from_path(tmp.path().join("test.espora")).unwrap();
+        db.insert_many(0..50).unwrap();
+
+        let rows = db.rows_from(45).collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![45, 46, 47, 48, 49], rows);
+
+        let rows = db
+            .rows_from(3)
+            .take(2)
+            .collect::<DbResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(vec![3, 4], rows);
+
+        assert_eq!(50, db.rows_from(0).count());
+        assert_eq!(0, db.rows_from(50).count());
+    }
+
+    #[test]
+    fn test_db_lock_writes_reloads_last_page() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let mut other = Db::<i64, 64>::from_path(&path).unwrap();
+
+        let lock = db.lock_writes().unwrap();
+        db.insert(1).unwrap();
+        drop(lock);
+
+        let _lock = other.lock_writes().unwrap();
+        other.insert(2).unwrap();
+
+        let rows = other.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2], rows);
+    }
+
+    #[test]
+    fn test_db_try_lock_writes() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let mut other = Db::<i64, 64>::from_path(&path).unwrap();
+
+        let lock = db.try_lock_writes().unwrap();
+        assert!(lock.is_some());
+        assert!(other.try_lock_writes().unwrap().is_none());
+        assert!(other
+            .lock_writes_timeout(Duration::from_millis(20))
+            .is_err_and(|err| matches!(err, Error::WouldBlock)));
+
+        drop(lock);
+        assert!(other.try_lock_writes().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_db_lock_reads() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let mut reader = Db::<i64, 64>::from_path(&path).unwrap();
+        let mut writer = Db::<i64, 64>::from_path(&path).unwrap();
+
+        let lock = db.lock_reads().unwrap();
+        let other_lock = reader.lock_reads().unwrap();
+        assert!(writer.try_lock_writes().unwrap().is_none());
+
+        drop(lock);
+        assert!(writer.try_lock_writes().unwrap().is_none());
+
+        drop(other_lock);
+        assert!(writer.try_lock_writes().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_db_read_only() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        assert!(Db::<i64, 64>::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert_many(0..10).unwrap();
+
+        let mut db = Db::<i64, 64>::open_read_only(&path).unwrap();
+        assert!(matches!(db.insert(10), Err(Error::ReadOnly)));
+        assert!(matches!(db.delete(|_| true), Err(Error::ReadOnly)));
+        assert!(matches!(db.clear(), Err(Error::ReadOnly)));
+
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!((0..10).collect::<Vec<_>>(), rows);
+    }
+
+    #[test]
+    fn test_db_create_if_missing() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let err = Db::<i64, 64>::builder()
+            .create_if_missing(false)
+            .build::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(!path.exists());
+
+        Db::<i64, 64>::from_path(&path).unwrap();
+        assert!(Db::<i64, 64>::builder()
+            .create_if_missing(false)
+            .build::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_db_header_mismatch() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert(1).unwrap();
+
+        let err = Db::<i64, 128>::from_path(&path).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("rows of 64 bytes"));
+
+        let err = Db::<i64, 64, 512>::from_path(&path).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let other = tmp.path().join("other.espora");
+        std::fs::write(&other, b"Rinha de Backend 2024").unwrap();
+        let err = Db::<i64, 64>::from_path(&other).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!("not an espora database", err.to_string());
+    }
+
+    #[test]
+    fn test_db_fixed_size_prefix() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let format = PageFormat::<DEFAULT_PAGE_SIZE> {
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            layout: RowLayout {
+                prefix: SizePrefix::Fixed,
+                ..RowLayout::default()
+            },
+            headerless: false,
+        };
+        let mut page = format.new_page::<64>();
+        page.insert(&bitcode::serialize(&1_i64).unwrap()).unwrap();
+        page.insert(&bitcode::serialize(&2_i64).unwrap()).unwrap();
+        let mut file = format.header::<64>();
+        assert_eq!(1, u16::from_be_bytes([file[8], file[9]]));
+        file.extend_from_slice(&format.seal(&page));
+        std::fs::write(&path, file).unwrap();
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        db.insert(3).unwrap();
+        db.delete(|row| *row == 2).unwrap();
+
+        let mut db = Db::<i64, 64>::from_path(&path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 3], rows);
+
+        let mut header = [0; 8];
+        File::open(&path)
+            .unwrap()
+            .read_exact_at(&mut header, 4096 + 2 * 64)
+            .unwrap();
+        assert_eq!(
+            bitcode::serialize(&3_i64).unwrap().len() as u64,
+            u64::from_be_bytes(header)
+        );
+    }
+
+    #[test]
+    fn test_db_preallocate() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let open = || {
+            Db::<i64, 1024>::builder()
+                .preallocate(1024 * 1024)
+                .build::<i64, 1024, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+                .unwrap()
+        };
+
+        let mut db = open();
+        assert_eq!(1024 * 1024, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(0, db.rows().count());
+        assert_eq!(0, db.rows_reverse().count());
+
+        db.insert_many(1..=5).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], rows);
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![5, 4, 3, 2, 1], rows);
+
+        let mut db = open();
+        db.insert(6).unwrap();
+        assert_eq!(1024 * 1024, std::fs::metadata(&path).unwrap().len());
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], rows);
+    }
+
+    #[test]
+    fn test_db_insert_returns_offset() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+
+        let offsets = (1..=6)
+            .map(|row| db.insert(row).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1024, 2048, 3072, 4096, 5120], offsets);
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        assert_eq!(6144, db.insert(7).unwrap());
+        let rows = db.rows_with_offset().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(Some(&(6144, 7)), rows.last());
+    }
+
+    #[test]
+    fn test_db_get() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<String, 1024>::from_path(tmp.path().join("test.espora")).unwrap();
+
+        let offsets = ["Rinha", "de", "Backend", "2024", "!"]
+            .into_iter()
+            .map(|row| (db.insert(row.to_string()).unwrap(), row))
+            .collect::<Vec<_>>();
+
+        for (offset, row) in offsets.iter().rev() {
+            assert_eq!(Some(row.to_string()), db.get(*offset).unwrap());
+        }
+        assert_eq!(Some(String::from("Backend")), db.get(offsets[2].0).unwrap());
+
+        db.delete(|row| row == "de").unwrap();
+        assert_eq!(None, db.get(offsets[1].0).unwrap());
+        assert_eq!(None, db.get(5120).unwrap());
+        assert_eq!(None, db.get(1024 * 1024).unwrap());
+        assert!(matches!(db.get(100), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_db_index() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<(u32, String), 64>::from_path(tmp.path().join("test.espora")).unwrap();
+        for id in 1..=100 {
+            db.insert((id, format!("transaction {id}"))).unwrap();
+        }
+
+        let key = |row: &(u32, String)| row.0;
+        let mut index = db.build_index(key).unwrap();
+        db.insert_indexed((101, String::from("new")), &mut index, key)
+            .unwrap();
+        assert_eq!(101, index.len());
+
+        for id in [101, 1, 64, 65, 100] {
+            let row = db.get(index[&id]).unwrap().unwrap();
+            assert_eq!(id, row.0);
+        }
+        assert_eq!("new", db.get(index[&101]).unwrap().unwrap().1);
+        assert!(!index.contains_key(&102));
+    }
+
+    #[test]
+    fn test_db_range() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 64>::from_path(tmp.path().join("test.espora")).unwrap();
+        db.insert_many(1..=100).unwrap();
+
+        let rows = db.range(10 * 64, 90 * 64).collect::<DbResult<Vec<_>>>();
+        assert_eq!((11..=90).collect::<Vec<_>>(), rows.unwrap());
+
+        let rows = db
+            .range(10 * 64 + 1, 90 * 64 + 1)
+            .collect::<DbResult<Vec<_>>>();
+        assert_eq!((12..=91).collect::<Vec<_>>(), rows.unwrap());
+
+        assert_eq!(0, db.range(50 * 64, 50 * 64).count());
+        assert_eq!(0, db.range(200 * 64, 300 * 64).count());
+        assert_eq!(100, db.range(0, u64::MAX).count());
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_db_export_csv() {
+        #[derive(Debug, Clone, PartialEq, Serialize, serde::Deserialize)]
+        struct Transaction {
+            value: i64,
+            kind: char,
+            description: String,
+        }
+
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<Transaction, 128>::from_path(tmp.path().join("test.espora")).unwrap();
+        let transactions = [(1000, 'c', "salario"), (-250, 'd', "mercado, feira")].map(
+            |(value, kind, description)| Transaction {
+                value,
+                kind,
+                description: description.to_string(),
+            },
+        );
+        for transaction in &transactions {
+            db.insert(transaction.clone()).unwrap();
+        }
+
+        let mut out = Vec::new();
+        db.export_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("value,kind,description\n"));
+
+        let rows = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<Vec<Transaction>, _>>()
+            .unwrap();
+        assert_eq!(transactions.to_vec(), rows);
+    }
+
+    #[test]
+    fn test_db_import() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<(u32, String), 64>::from_path(&path).unwrap();
+        let count = db
+            .import((0..10_000).map(|id| (id, format!("row {id}"))))
+            .unwrap();
+        assert_eq!(10_000, count);
+        assert_eq!(Some(Duration::from_secs(0)), db.sync_writes);
+        drop(db);
+
+        let mut db = Db::<(u32, String), 64>::from_path(&path).unwrap();
+        assert_eq!(10_000, db.count().unwrap());
+        assert_eq!(Some((9_999, String::from("row 9999"))), db.last().unwrap());
+    }
+
+    #[test]
+    fn test_db_file_len_and_page_count() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 1024>::from_path(tmp.path().join("test.espora")).unwrap();
+        assert_eq!(4096, db.file_len().unwrap());
+        assert_eq!(0, db.page_count().unwrap());
+
+        db.insert_many(1..=4).unwrap();
+        assert_eq!(1, db.page_count().unwrap());
+
+        db.insert(5).unwrap();
+        assert_eq!(3 * 4096, db.file_len().unwrap());
+        assert_eq!(2, db.page_count().unwrap());
+    }
+
+    #[test]
+    fn test_db_compact() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+
+        db.insert_many(1..=20).unwrap();
+        db.delete(|row| row % 2 == 0).unwrap();
+        assert_eq!(6 * 4096, db.file_len().unwrap());
+
+        db.compact().unwrap();
+        assert_eq!(4 * 4096, std::fs::metadata(&path).unwrap().len());
+        assert!(!tmp.path().join("test.espora.compact").exists());
+        let rows = db.rows_with_offset().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            (1..=20).step_by(2).collect::<Vec<_>>(),
+            rows.iter().map(|row| row.1).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(&(9 * 1024, 19)), rows.last());
+
+        assert_eq!(10 * 1024, db.insert(21).unwrap());
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        assert_eq!(11, db.count().unwrap());
+        assert_eq!(Some(21), db.last().unwrap());
+    }
+
+    #[test]
+    fn test_db_expire_rows() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<(u64, i64), 1024>::from_path(tmp.path().join("test.espora")).unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let hour = 60 * 60;
+
+        db.insert_many([
+            (now - 3 * hour, 1),
+            (now - 2 * hour, 2),
+            (now - 10, 3),
+            (now, 4),
+            (now - 5 * hour, 5),
+        ])
+        .unwrap();
+        db.expire_after(Duration::from_secs(hour), |(created_at, _)| {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(*created_at)
+        });
+
+        let values = |rows: Vec<(u64, i64)>| rows.into_iter().map(|row| row.1).collect::<Vec<_>>();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![3, 4], values(rows));
+        let rows = db.rows_reverse().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![4, 3], values(rows));
+        assert_eq!(Some(4), db.last().unwrap().map(|row| row.1));
+        assert_eq!(5, db.count().unwrap());
+
+        db.compact().unwrap();
+        assert_eq!(2, db.count().unwrap());
+        assert_eq!(2 * 4096, db.file_len().unwrap());
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(vec![3, 4], values(rows));
+    }
+
+    #[test]
+    fn test_db_rows_raw() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<(i64, String), 64>::from_path(tmp.path().join("test.espora")).unwrap();
+        for (balance, description) in [(100, "deposito"), (-50, "pix"), (25, "troco")] {
+            db.insert((balance, description.to_string())).unwrap();
+        }
+        db.delete(|row| row.1 == "pix").unwrap();
+
+        let rows = db.rows_raw().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            vec![0, 128],
+            rows.iter().map(RawRow::offset).collect::<Vec<_>>()
+        );
+
+        let last = rows.last().unwrap();
+        let (balance, description) = bitcode::deserialize::<(i64, String)>(last).unwrap();
+        assert_eq!((25, String::from("troco")), (balance, description));
+    }
+
+    #[test]
+    fn test_db_page_cache() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 1024>::builder()
+            .page_cache(2)
+            .build::<i64, 1024, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .unwrap();
+
+        let page_reads = || PAGE_READS.with(|reads| reads.get());
+
+        db.insert_many(1..=10).unwrap();
+        let reads = page_reads();
+        let rows = db.rows_reverse().take(3).collect::<DbResult<Vec<_>>>();
+        assert_eq!(vec![10, 9, 8], rows.unwrap());
+        assert_eq!(2, page_reads() - reads);
+
+        // Writes clear the cache
+        db.insert(11).unwrap();
+        let reads = page_reads();
+        let rows = db.rows_reverse().take(3).collect::<DbResult<Vec<_>>>();
+        assert_eq!(vec![11, 10, 9], rows.unwrap());
+        assert_eq!(1, page_reads() - reads);
+
+        let reads = page_reads();
+        let rows = db.rows_reverse().take(3).collect::<DbResult<Vec<_>>>();
+        assert_eq!(vec![11, 10, 9], rows.unwrap());
+        assert_eq!(0, page_reads() - reads);
+
+        // Cached pages aren't read again, so wiping them from the file goes unnoticed
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len() - db.format.header_size();
+        file.write_all_at(&vec![0; len as usize], db.format.header_size())
+            .unwrap();
+
+        let rows = db.rows_reverse().take(3).collect::<DbResult<Vec<_>>>();
+        assert_eq!(vec![11, 10, 9], rows.unwrap());
+        let mut uncached = Db::<i64, 1024>::open_read_only(&path).unwrap();
+        assert_eq!(0, uncached.rows_reverse().count());
+    }
+
+    #[test]
+    fn test_db_search_by() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<(u64, String), 64>::from_path(tmp.path().join("test.espora")).unwrap();
+
+        // Three pages of rows ordered by their key
+        db.insert_many((0..150).map(|i| (i * 10, format!("Rinha {i}"))))
+            .unwrap();
+        // Every row of the second page
+        db.delete(|(key, _)| (640..1280).contains(key)).unwrap();
+
+        let found = db.search_by(420, |(key, _)| *key).unwrap();
+        assert_eq!(Some((42 * 64, (420, String::from("Rinha 42")))), found);
+        let found = db.search_by(1490, |(key, _)| *key).unwrap();
+        assert_eq!(
+            Some((2 * 4096 + 21 * 64, (1490, String::from("Rinha 149")))),
+            found
+        );
+
+        assert_eq!(None, db.search_by(425, |(key, _)| *key).unwrap());
+        assert_eq!(None, db.search_by(700, |(key, _)| *key).unwrap());
+        assert_eq!(None, db.search_by(1500, |(key, _)| *key).unwrap());
+    }
+
+    #[test]
+    fn test_db_first_and_last() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        assert_eq!(None, db.first().unwrap());
+        assert_eq!(None, db.last().unwrap());
+
+        db.insert_many(1..=4).unwrap();
+        assert_eq!(Some(1), db.first().unwrap());
+        assert_eq!(Some(4), db.last().unwrap());
+
+        db.insert(5).unwrap();
+        assert_eq!(Some(5), db.last().unwrap());
+
+        db.delete(|row| *row == 5).unwrap();
+        assert_eq!(Some(4), db.last().unwrap());
+
+        let mut db = Db::<i64, 1024>::from_path(&path).unwrap();
+        assert_eq!(Some(1), db.first().unwrap());
+        assert_eq!(Some(4), db.last().unwrap());
+    }
+
+    #[test]
+    fn test_db_backup() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let backup_path = tmp.path().join("backup.espora");
+
+        let mut db = Db::<(i64, String), 64>::from_path(&path).unwrap();
+        for i in 0..100 {
+            db.insert((i, format!("Rinha {i}"))).unwrap();
+        }
+        db.backup(&backup_path).unwrap();
+        db.insert((100, String::from("Rinha 100"))).unwrap();
+
+        let mut backup = Db::<(i64, String), 64>::from_path(&backup_path).unwrap();
+        let rows = db.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        let backup_rows = backup.rows().collect::<DbResult<Vec<_>>>().unwrap();
+        assert_eq!(rows[..100], backup_rows);
+    }
+
+    #[test]
+    fn test_db_verify() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<(i64, String), 64>::from_path(&path).unwrap();
+        for i in 0..256 {
+            db.insert((i, format!("Rinha {i}"))).unwrap();
+        }
+        assert!(db.verify().unwrap().is_empty());
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[1 << 1], 2 * 4096 + 64).unwrap();
+        file.write_all_at(&[0xfe, 0xff, 0xff, 0x7f], 4 * 4096)
+            .unwrap();
+
+        assert_eq!(vec![4096, 3 * 4096], db.verify().unwrap());
+    }
 }
@@ -1,11 +1,159 @@
-use std::os::fd::RawFd;
+//! Advisory whole-file locks, using `flock` on Unix and `LockFileEx` on Windows.
 
+use std::io;
+
+pub(crate) use sys::{raw_file, RawFile};
+
+/// Holds a lock over the database file until dropped.
 pub struct LockHandle {
-    pub(crate) fd: RawFd,
+    file: RawFile,
 }
 
 impl Drop for LockHandle {
     fn drop(&mut self) {
-        unsafe { libc::flock(self.fd, libc::LOCK_UN) };
+        sys::unlock(self.file);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// Blocks until the lock is acquired.
+pub(crate) fn lock(file: RawFile, mode: LockMode) -> io::Result<LockHandle> {
+    sys::lock(file, mode, true)?;
+    Ok(LockHandle { file })
+}
+
+/// Returns `None` instead of blocking when a conflicting lock is held by another handle.
+pub(crate) fn try_lock(file: RawFile, mode: LockMode) -> io::Result<Option<LockHandle>> {
+    Ok(sys::lock(file, mode, false)?.then_some(LockHandle { file }))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        io,
+        os::fd::{AsRawFd, RawFd},
+    };
+
+    use super::LockMode;
+
+    pub type RawFile = RawFd;
+
+    pub fn raw_file(file: &impl AsRawFd) -> RawFile {
+        file.as_raw_fd()
+    }
+
+    pub fn lock(file: RawFile, mode: LockMode, blocking: bool) -> io::Result<bool> {
+        let mut operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+        if !blocking {
+            operation |= libc::LOCK_NB;
+        }
+
+        if unsafe { libc::flock(file, operation) } == 0 {
+            return Ok(true);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock if !blocking => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    pub fn unlock(file: RawFile) {
+        unsafe { libc::flock(file, libc::LOCK_UN) };
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::{io, mem, os::windows::io::AsRawHandle};
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_LOCK_VIOLATION, HANDLE},
+        Storage::FileSystem::{
+            LockFileEx, UnlockFile, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+        },
+        System::IO::OVERLAPPED,
+    };
+
+    use super::LockMode;
+
+    /// Kept as an integer so lock handles can be sent across threads.
+    pub type RawFile = isize;
+
+    pub fn raw_file(file: &impl AsRawHandle) -> RawFile {
+        file.as_raw_handle() as RawFile
+    }
+
+    pub fn lock(file: RawFile, mode: LockMode, blocking: bool) -> io::Result<bool> {
+        let mut flags = 0;
+        if let LockMode::Exclusive = mode {
+            flags |= LOCKFILE_EXCLUSIVE_LOCK;
+        }
+        if !blocking {
+            flags |= LOCKFILE_FAIL_IMMEDIATELY;
+        }
+
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        let locked = unsafe {
+            LockFileEx(
+                file as HANDLE,
+                flags,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            )
+        };
+        if locked != 0 {
+            return Ok(true);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(code) if !blocking && code == ERROR_LOCK_VIOLATION as i32 => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    pub fn unlock(file: RawFile) {
+        unsafe { UnlockFile(file as HANDLE, 0, 0, u32::MAX, u32::MAX) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let file = File::create(&path).unwrap();
+        let other = File::open(&path).unwrap();
+
+        let handle = lock(raw_file(&file), LockMode::Exclusive).unwrap();
+        assert!(try_lock(raw_file(&other), LockMode::Shared)
+            .unwrap()
+            .is_none());
+
+        drop(handle);
+        let handle = try_lock(raw_file(&other), LockMode::Shared).unwrap();
+        assert!(handle.is_some());
+        assert!(try_lock(raw_file(&file), LockMode::Shared)
+            .unwrap()
+            .is_some());
     }
 }
@@ -1,89 +1,425 @@
 use std::{
+    cmp::Ordering,
     io::{Cursor, Seek, Write},
-    iter,
+    ops::Deref,
 };
 
-use serde::Serialize;
+use crate::{DbResult, Error};
 
-use crate::DbResult;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
 
-pub const PAGE_SIZE: usize = 4096;
+/// Reserved bit of the fixed row size header marking a deleted row.
+const TOMBSTONE: u64 = 1 << 63;
 
-#[derive(Debug)]
-pub struct Page<const ROW_SIZE: usize> {
-    data: Vec<u8>,
+/// Bytes of the CRC32 rows carry right after their size prefix, in files written with checksums.
+const CHECKSUM_SIZE: usize = 4;
+
+/// Longest LEB128 encoding of a `u64`.
+const MAX_VARINT_SIZE: usize = 10;
+
+/// How the size of each row is stored at the start of its slot. A zero size marks an empty slot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SizePrefix {
+    /// A big-endian `u64` whose highest bit marks deleted rows, as written by version 1 files.
+    Fixed,
+    /// A LEB128 varint of the size shifted left by one, whose lowest bit marks deleted rows.
+    #[default]
+    Varint,
+}
+
+/// The decoded size prefix of a row that was written to a slot.
+#[derive(Debug, Clone, Copy)]
+struct RowPrefix {
+    size: u64,
+    deleted: bool,
+    /// Bytes taken by the prefix itself.
+    len: usize,
+}
+
+impl SizePrefix {
+    fn encode(self, size: usize) -> Vec<u8> {
+        match self {
+            Self::Fixed => (size as u64).to_be_bytes().to_vec(),
+            Self::Varint => {
+                let mut value = (size as u64) << 1;
+                let mut bytes = Vec::with_capacity(MAX_VARINT_SIZE);
+                loop {
+                    let byte = (value & 0x7f) as u8;
+                    value >>= 7;
+                    if value == 0 {
+                        bytes.push(byte);
+                        return bytes;
+                    }
+                    bytes.push(byte | 0x80);
+                }
+            }
+        }
+    }
+
+    /// Reads the prefix at the start of a slot, or `None` when the slot is empty.
+    fn decode(self, slot: &[u8]) -> Option<RowPrefix> {
+        match self {
+            Self::Fixed => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&slot[0..8]);
+                let header = u64::from_be_bytes(buf);
+                (header != 0).then_some(RowPrefix {
+                    size: header & !TOMBSTONE,
+                    deleted: header & TOMBSTONE != 0,
+                    len: 8,
+                })
+            }
+            Self::Varint => {
+                let mut value = 0;
+                for (index, byte) in slot.iter().take(MAX_VARINT_SIZE).enumerate() {
+                    value |= u64::from(byte & 0x7f) << (7 * index);
+                    if byte & 0x80 == 0 {
+                        return (value != 0).then_some(RowPrefix {
+                            size: value >> 1,
+                            deleted: value & 1 != 0,
+                            len: index + 1,
+                        });
+                    }
+                }
+
+                // An unterminated varint can only come from a corrupted slot
+                Some(RowPrefix {
+                    size: u64::MAX,
+                    deleted: false,
+                    len: MAX_VARINT_SIZE.min(slot.len()),
+                })
+            }
+        }
+    }
+
+    /// Flags the row at the start of the slot as deleted, without changing the prefix length.
+    fn mark_deleted(self, slot: &mut [u8]) {
+        match self {
+            Self::Fixed => slot[0] |= (TOMBSTONE >> 56) as u8,
+            Self::Varint => slot[0] |= 1,
+        }
+    }
+
+    /// Largest serialized row that fits in a slot along with its prefix and `checksum_size` bytes.
+    fn max_row_size(self, row_size: usize, checksum_size: usize) -> usize {
+        let available = row_size.saturating_sub(checksum_size);
+        match self {
+            Self::Fixed => available.saturating_sub(8),
+            Self::Varint => (1..=MAX_VARINT_SIZE)
+                .map(|len| available.saturating_sub(len))
+                .find(|size| self.encode(*size).len() + size <= available)
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// How rows are laid out in their slots. Taken from the header of existing files, so files written
+/// with or without checksums are read the same way whatever features the crate was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowLayout {
+    pub prefix: SizePrefix,
+    /// Whether each row carries a CRC32 of its payload right after the size prefix.
+    pub checksums: bool,
+}
+
+impl Default for RowLayout {
+    /// The layout of new files, with checksums only when built with the `crc` feature.
+    fn default() -> Self {
+        Self {
+            prefix: SizePrefix::default(),
+            checksums: cfg!(feature = "crc"),
+        }
+    }
+}
+
+impl RowLayout {
+    fn checksum_size(self) -> usize {
+        if self.checksums {
+            CHECKSUM_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Largest serialized row that fits in a slot of `row_size` bytes.
+    fn max_row_size(self, row_size: usize) -> usize {
+        self.prefix.max_row_size(row_size, self.checksum_size())
+    }
+
+    /// Whether the slot holds a row, live or deleted, whose size fits in it.
+    pub fn holds_row(self, slot: &[u8]) -> bool {
+        self.prefix.decode(slot).is_some_and(|prefix| {
+            let start = prefix.len + self.checksum_size();
+            prefix.size <= slot.len().saturating_sub(start) as u64
+        })
+    }
+}
+
+/// The bytes of a page, either its own or shared with a mapping of the file, in which case they're
+/// only copied once the page is changed.
+#[derive(Debug, Clone)]
+enum PageData {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped {
+        map: std::sync::Arc<memmap2::Mmap>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl PageData {
+    fn to_mut(&mut self) -> &mut Vec<u8> {
+        #[cfg(feature = "mmap")]
+        if let Self::Mapped { .. } = self {
+            *self = Self::Owned(self.to_vec());
+        }
+
+        match self {
+            Self::Owned(data) => data,
+            #[cfg(feature = "mmap")]
+            Self::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+impl Deref for PageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(data) => data,
+            #[cfg(feature = "mmap")]
+            Self::Mapped { map, offset, len } => &map[*offset..*offset + *len],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Page<const ROW_SIZE: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    data: PageData,
     free: usize,
+    layout: RowLayout,
 }
 
-impl<const ROW_SIZE: usize> Page<ROW_SIZE> {
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize> Page<ROW_SIZE, PAGE_SIZE> {
+    /// A page with the row layout of new files. The databases get theirs from
+    /// [`PageFormat`](crate::format::PageFormat) instead, as it depends on the file header.
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_layout(RowLayout::default())
+    }
+
+    pub fn with_layout(layout: RowLayout) -> Self {
         Self {
-            data: Vec::with_capacity(PAGE_SIZE),
+            data: PageData::Owned(Vec::with_capacity(PAGE_SIZE)),
             free: PAGE_SIZE,
+            layout,
         }
     }
 
+    #[cfg(test)]
     pub fn from_bytes(data: Vec<u8>) -> Self {
-        let free = {
-            let mut cursor = 0;
-            let last_page_offset = iter::from_fn(|| {
-                let offset = cursor * ROW_SIZE;
-                if offset + ROW_SIZE > data.len() {
-                    return None;
-                }
-                cursor += 1;
+        Self::from_bytes_with_layout(data, RowLayout::default())
+    }
 
-                if data[offset..offset + 8] != [0; 8] {
-                    Some(offset + ROW_SIZE)
-                } else {
-                    None
-                }
-            })
-            .last()
-            .unwrap_or(0);
-            PAGE_SIZE - last_page_offset
+    pub fn from_bytes_with_layout(data: Vec<u8>, layout: RowLayout) -> Self {
+        let free = Self::free_space(&data, layout.prefix);
+        Self {
+            data: PageData::Owned(data),
+            free,
+            layout,
+        }
+    }
+
+    /// A page reading its bytes straight from the map, from `offset` on.
+    #[cfg(feature = "mmap")]
+    pub fn from_map(map: std::sync::Arc<memmap2::Mmap>, offset: usize, layout: RowLayout) -> Self {
+        let data = PageData::Mapped {
+            map,
+            offset,
+            len: PAGE_SIZE,
         };
+        let free = Self::free_space(&data, layout.prefix);
+        Self { data, free, layout }
+    }
 
-        Self { data, free }
+    /// Space left after the last slot that was written to, deleted rows included.
+    fn free_space(data: &[u8], prefix: SizePrefix) -> usize {
+        let used = (0..data.len() / ROW_SIZE)
+            .rev()
+            .find(|slot| {
+                let offset = slot * ROW_SIZE;
+                prefix.decode(&data[offset..offset + ROW_SIZE]).is_some()
+            })
+            .map(|slot| (slot + 1) * ROW_SIZE)
+            .unwrap_or(0);
+        PAGE_SIZE - used
     }
 
-    pub fn insert<S: Serialize>(&mut self, row: S) -> DbResult<()> {
-        let serialized = bitcode::serialize(&row)?;
-        let size = serialized.len() as u64;
-        let size = size.to_be_bytes();
+    pub fn insert(&mut self, row: &[u8]) -> DbResult<()> {
+        let slot = Self::encode_row(self.layout, row)?;
 
-        let mut cursor = Cursor::new(&mut self.data);
+        let mut cursor = Cursor::new(self.data.to_mut());
         cursor.seek(std::io::SeekFrom::Start((PAGE_SIZE - self.free) as u64))?;
 
-        self.free -= cursor.write(&size)?;
-        self.free -= cursor.write(&serialized)?;
-        self.free -= cursor.write(&vec![0; ROW_SIZE - (serialized.len() + size.len())])?;
+        self.free -= cursor.write(&slot)?;
 
         Ok(())
     }
 
     pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
-        let mut cursor = 0;
-        iter::from_fn(move || {
-            let offset = cursor * ROW_SIZE;
-            if offset + ROW_SIZE > self.data.len() {
-                return None;
-            }
+        self.entries().map(|(_, row)| row)
+    }
 
-            let row = &self.data[offset..offset + ROW_SIZE];
-            let size = {
-                let mut buf = [0; 8];
-                buf.copy_from_slice(&row[0..8]);
-                u64::from_be_bytes(buf) as usize
+    /// Iterates over the live rows of the page along with their slot index, skipping tombstones.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        let used = PAGE_SIZE - self.free;
+        (0..used / ROW_SIZE).filter_map(|index| self.get(index).map(|row| (index, row)))
+    }
+
+    /// The live row at the given slot, read straight from its offset. Returns `None` for empty,
+    /// deleted or out of range slots.
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        let prefix = self.header(index)?;
+        let offset = index * ROW_SIZE;
+        let start = (prefix.len + self.layout.checksum_size()).min(ROW_SIZE);
+        let size = prefix.size.min((ROW_SIZE - start) as u64) as usize;
+        Some(&self.data[offset + start..offset + start + size])
+    }
+
+    /// Binary searches the rows of a page sorted by the key `extract` reads from them, like
+    /// [`slice::binary_search_by_key`]. Only the probed rows are handed to `extract`, and deleted
+    /// slots are skipped. Returns the slot of a matching row, or the slot a row with that key
+    /// would be inserted at.
+    pub fn search_by<K: Ord>(&self, key: &K, extract: impl Fn(&[u8]) -> K) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, (PAGE_SIZE - self.free) / ROW_SIZE);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let probe = (mid..high).find_map(|index| self.get(index).map(|row| (index, row)));
+            let Some((index, row)) = probe else {
+                high = mid;
+                continue;
             };
 
-            if size == 0 {
-                return None;
+            match extract(row).cmp(key) {
+                Ordering::Less => low = index + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(index),
             }
+        }
+        Err(low)
+    }
 
-            cursor += 1;
-            Some(&row[8..8 + size])
-        })
+    /// Marks the row at the given slot as deleted. Returns `false` if there is no live row there.
+    pub fn delete(&mut self, index: usize) -> bool {
+        match self.header(index) {
+            Some(_) => {
+                let offset = index * ROW_SIZE;
+                self.layout
+                    .prefix
+                    .mark_deleted(&mut self.data.to_mut()[offset..offset + ROW_SIZE]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the live row at the given slot instead of leaving a tombstone, moving the slots after
+    /// it one slot down so the page stays dense. The slot freed at the end is zeroed, so the page
+    /// gets the same free space when read back from its bytes. Returns `false` if there is no live
+    /// row there.
+    ///
+    /// [`crate::Db`] only uses it while compacting, as moving rows would change their offsets.
+    pub fn remove(&mut self, index: usize) -> bool {
+        if self.header(index).is_none() {
+            return false;
+        }
+
+        let used = PAGE_SIZE - self.free;
+        let offset = index * ROW_SIZE;
+        let data = self.data.to_mut();
+        data.copy_within(offset + ROW_SIZE..used, offset);
+        data[used - ROW_SIZE..used].fill(0);
+        self.free = Self::free_space(&self.data, self.layout.prefix);
+        true
+    }
+
+    /// Overwrites the live row at the given slot with a new serialized row, which must still fit in
+    /// a slot. Fails with [`Error::NotFound`] if there is no live row there.
+    pub fn update(&mut self, index: usize, row: &[u8]) -> DbResult<()> {
+        let slot = Self::encode_row(self.layout, row)?;
+        if self.header(index).is_none() {
+            return Err(Error::NotFound);
+        }
+
+        let offset = index * ROW_SIZE;
+        self.data.to_mut()[offset..offset + ROW_SIZE].copy_from_slice(&slot);
+        Ok(())
+    }
+
+    /// Whether the row at the given slot has a sane size header and, when the layout has
+    /// checksums, a payload matching its checksum.
+    pub fn is_intact(&self, index: usize) -> bool {
+        let prefix = match self.header(index) {
+            Some(prefix) => prefix,
+            None => return false,
+        };
+
+        let start = prefix.len + self.layout.checksum_size();
+        if prefix.size > ROW_SIZE.saturating_sub(start) as u64 {
+            return false;
+        }
+
+        if !self.layout.checksums {
+            return true;
+        }
+
+        let offset = index * ROW_SIZE + prefix.len;
+        let mut checksum = [0; CHECKSUM_SIZE];
+        checksum.copy_from_slice(&self.data[offset..offset + CHECKSUM_SIZE]);
+        let payload =
+            &self.data[offset + CHECKSUM_SIZE..offset + CHECKSUM_SIZE + prefix.size as usize];
+        crc32fast::hash(payload) == u32::from_be_bytes(checksum)
+    }
+
+    /// Lays out an already serialized row into a whole slot: the size prefix, the checksum if the
+    /// layout has one, the payload and the zero padding.
+    pub fn encode_row(layout: RowLayout, serialized: &[u8]) -> DbResult<Vec<u8>> {
+        let max = layout.max_row_size(ROW_SIZE);
+        if serialized.len() > max {
+            return Err(Error::RowTooLarge {
+                size: serialized.len(),
+                max,
+            });
+        }
+
+        let mut slot = Vec::with_capacity(ROW_SIZE);
+        slot.extend_from_slice(&layout.prefix.encode(serialized.len()));
+        if layout.checksums {
+            slot.extend_from_slice(&crc32fast::hash(serialized).to_be_bytes());
+        }
+        slot.extend_from_slice(serialized);
+        slot.resize(ROW_SIZE, 0);
+        Ok(slot)
+    }
+
+    /// Size prefix of the live row at the given slot, if any.
+    fn header(&self, index: usize) -> Option<RowPrefix> {
+        if index >= self.data.len() / ROW_SIZE {
+            return None;
+        }
+
+        let offset = index * ROW_SIZE;
+        self.layout
+            .prefix
+            .decode(&self.data[offset..offset + ROW_SIZE])
+            .filter(|prefix| !prefix.deleted)
+    }
+
+    /// Number of live rows in the page, without deserializing them.
+    pub fn row_count(&self) -> usize {
+        self.rows().count()
     }
 
     pub fn len(&self) -> usize {
@@ -91,11 +427,26 @@ impl<const ROW_SIZE: usize> Page<ROW_SIZE> {
     }
 
     pub fn available_rows(&self) -> usize {
-        (PAGE_SIZE - self.data.len()) / ROW_SIZE
+        self.free_bytes() / ROW_SIZE
+    }
+
+    /// Bytes left after the last written slot.
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Whether there's no slot left for another row.
+    pub fn is_full(&self) -> bool {
+        self.available_rows() == 0
+    }
+
+    /// Whether no row was ever written to the page, like the zeroed pages of a preallocated file.
+    pub fn is_unused(&self) -> bool {
+        self.free == PAGE_SIZE
     }
 }
 
-impl<const ROW_SIZE: usize> AsRef<[u8]> for Page<ROW_SIZE> {
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize> AsRef<[u8]> for Page<ROW_SIZE, PAGE_SIZE> {
     fn as_ref(&self) -> &[u8] {
         &self.data
     }
@@ -105,29 +456,40 @@ impl<const ROW_SIZE: usize> AsRef<[u8]> for Page<ROW_SIZE> {
 mod tests {
     use super::*;
 
+    /// Checksum bytes in the slots of new pages, which depend on the `crc` feature.
+    const CHECKSUM: usize = if cfg!(feature = "crc") {
+        CHECKSUM_SIZE
+    } else {
+        0
+    };
+
+    fn row<S: serde::Serialize>(row: S) -> Vec<u8> {
+        bitcode::serialize(&row).unwrap()
+    }
+
     #[test]
     fn test_insert_into_page() {
         let mut page = Page::<1024>::new();
         assert_eq!(4, page.available_rows());
-        page.insert(String::from("Rinha")).unwrap();
+        page.insert(&row(String::from("Rinha"))).unwrap();
         assert_eq!(3, page.available_rows());
-        page.insert(String::from("de")).unwrap();
+        page.insert(&row(String::from("de"))).unwrap();
         assert_eq!(2, page.available_rows());
-        page.insert(2024 as u64).unwrap();
+        page.insert(&row(2024_u64)).unwrap();
         assert_eq!(1, page.available_rows());
 
         let mut rows = page.rows();
         assert_eq!(
             "Rinha",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert_eq!(
             "de",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert_eq!(
             2024,
-            bitcode::deserialize::<u64>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<u64>(rows.next().unwrap()).unwrap()
         );
         assert!(rows.next().is_none());
     }
@@ -136,21 +498,21 @@ mod tests {
     fn test_initialize() {
         let page = Page::<1024>::new();
         assert_eq!(0, page.len());
-        assert_eq!(PAGE_SIZE, page.free);
+        assert_eq!(DEFAULT_PAGE_SIZE, page.free);
     }
 
     #[test]
     fn test_from_empty_bytes() {
         let page = Page::<1024>::from_bytes(vec![]);
         assert_eq!(0, page.len());
-        assert_eq!(PAGE_SIZE, page.free);
+        assert_eq!(DEFAULT_PAGE_SIZE, page.free);
     }
 
     #[test]
     fn test_from_bytes() {
         let mut page = Page::<1024>::from_bytes(vec![]);
-        page.insert(1).unwrap();
-        page.insert(2).unwrap();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
 
         let new_page = Page::<1024>::from_bytes(page.as_ref().to_vec());
         assert_eq!(page.len(), new_page.len());
@@ -161,30 +523,400 @@ mod tests {
     #[test]
     fn test_update_existing_page() {
         let mut page = Page::<1024>::from_bytes(vec![]);
-        page.insert("Rinha").unwrap();
-        page.insert("de").unwrap();
+        page.insert(&row("Rinha")).unwrap();
+        page.insert(&row("de")).unwrap();
 
         let mut page = Page::<1024>::from_bytes(page.as_ref().to_vec());
-        page.insert("Backend").unwrap();
-        page.insert("2024").unwrap();
+        page.insert(&row("Backend")).unwrap();
+        page.insert(&row("2024")).unwrap();
 
         let mut rows = page.rows();
         assert_eq!(
             "Rinha",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert_eq!(
             "de",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert_eq!(
             "Backend",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert_eq!(
             "2024",
-            bitcode::deserialize::<String>(&rows.next().unwrap()).unwrap()
+            bitcode::deserialize::<String>(rows.next().unwrap()).unwrap()
         );
         assert!(rows.next().is_none());
     }
+
+    #[test]
+    fn test_delete_row() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        page.insert(&row(3)).unwrap();
+
+        assert!(page.delete(1));
+        assert!(!page.delete(1));
+        assert!(!page.delete(3));
+
+        let rows = page
+            .rows()
+            .map(|row| bitcode::deserialize::<i32>(row).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 3], rows);
+
+        let page = Page::<1024>::from_bytes(page.as_ref().to_vec());
+        assert_eq!(1, page.available_rows());
+        assert_eq!(2, page.row_count());
+    }
+
+    #[test]
+    fn test_row_count() {
+        let mut page = Page::<1024>::new();
+        assert_eq!(0, page.row_count());
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        assert_eq!(2, page.row_count());
+
+        let mut buf = page.as_ref().to_vec();
+        buf.resize(DEFAULT_PAGE_SIZE, 0);
+        assert_eq!(2, Page::<1024>::from_bytes(buf).row_count());
+    }
+
+    #[test]
+    fn test_custom_page_size() {
+        let mut page = Page::<128, 512>::new();
+        assert_eq!(4, page.available_rows());
+        page.insert(&row(1)).unwrap();
+        assert_eq!(3, page.available_rows());
+
+        let mut buf = page.as_ref().to_vec();
+        buf.resize(512, 0);
+        let page = Page::<128, 512>::from_bytes(buf);
+        assert_eq!(512 - 128, page.free);
+        assert_eq!(1, page.row_count());
+    }
+
+    #[test]
+    fn test_insert_oversized_row() {
+        let mut page = Page::<32>::new();
+        page.insert(&row(String::from("Rinha"))).unwrap();
+        assert!(matches!(
+            page.insert(&row("Rinha de Backend".repeat(2))),
+            Err(Error::RowTooLarge { max, .. }) if max == 32 - 1 - CHECKSUM
+        ));
+
+        assert_eq!(32, page.len());
+        assert_eq!(1, page.row_count());
+    }
+
+    #[test]
+    fn test_insert_row_at_fixed_size_limit() {
+        let mut page = Page::<64>::with_layout(RowLayout {
+            prefix: SizePrefix::Fixed,
+            ..RowLayout::default()
+        });
+        let max = 64 - 8 - CHECKSUM;
+        page.insert(&[1; 64 - 8 - CHECKSUM]).unwrap();
+        assert!(matches!(
+            page.insert(&[1; 64 + 1]),
+            Err(Error::RowTooLarge { size: 65, max: limit }) if limit == max
+        ));
+
+        assert_eq!(64, page.len());
+        assert_eq!(Some(&[1; 64 - 8 - CHECKSUM][..]), page.get(0));
+    }
+
+    #[test]
+    fn test_is_intact() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        assert!(page.is_intact(0));
+        assert!(page.is_intact(1));
+        assert!(!page.is_intact(2));
+
+        let mut data = page.as_ref().to_vec();
+        let prefix = SizePrefix::Varint.encode(2048);
+        data[1024..1024 + prefix.len()].copy_from_slice(&prefix);
+        let page = Page::<1024>::from_bytes(data);
+        assert!(page.is_intact(0));
+        assert!(!page.is_intact(1));
+        assert_eq!(2, page.rows().count());
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(String::from("Rinha"))).unwrap();
+        assert!(page.is_intact(0));
+
+        let mut data = page.as_ref().to_vec();
+        data[1 + CHECKSUM] ^= 0xff;
+        assert!(!Page::<1024>::from_bytes(data).is_intact(0));
+    }
+
+    #[test]
+    fn test_from_bytes_with_trailing_zeroed_slots() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+
+        let mut data = page.as_ref().to_vec();
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let page = Page::<1024>::from_bytes(data);
+        assert_eq!(DEFAULT_PAGE_SIZE - 2 * 1024, page.free);
+        assert_eq!(2, page.row_count());
+    }
+
+    #[test]
+    fn test_from_bytes_with_hole() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        page.insert(&row(3)).unwrap();
+
+        let mut data = page.as_ref().to_vec();
+        data[1024..2048].fill(0);
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let page = Page::<1024>::from_bytes(data);
+        assert_eq!(DEFAULT_PAGE_SIZE - 3 * 1024, page.free);
+
+        let rows = page
+            .rows()
+            .map(|row| bitcode::deserialize::<i32>(row).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 3], rows);
+    }
+
+    #[test]
+    fn test_available_rows_from_full_buffer() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+
+        let mut data = page.as_ref().to_vec();
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let mut page = Page::<1024>::from_bytes(data);
+        assert_eq!(3, page.available_rows());
+
+        page.insert(&row(2)).unwrap();
+        assert_eq!(2, page.available_rows());
+        assert_eq!(DEFAULT_PAGE_SIZE, page.len());
+    }
+
+    #[test]
+    fn test_varint_prefix() {
+        assert_eq!(vec![0x02], SizePrefix::Varint.encode(1));
+        assert_eq!(vec![0x7e], SizePrefix::Varint.encode(63));
+        assert_eq!(vec![0x80, 0x01], SizePrefix::Varint.encode(64));
+        assert_eq!(vec![0x80, 0x40], SizePrefix::Varint.encode(4096));
+
+        for size in [1, 63, 64, 4096, u32::MAX as usize] {
+            let mut slot = SizePrefix::Varint.encode(size);
+            let prefix = SizePrefix::Varint.decode(&slot).unwrap();
+            assert_eq!(size as u64, prefix.size);
+            assert_eq!(slot.len(), prefix.len);
+            assert!(!prefix.deleted);
+
+            SizePrefix::Varint.mark_deleted(&mut slot);
+            let prefix = SizePrefix::Varint.decode(&slot).unwrap();
+            assert_eq!(size as u64, prefix.size);
+            assert!(prefix.deleted);
+        }
+
+        assert!(SizePrefix::Varint.decode(&[0; 8]).is_none());
+    }
+
+    #[test]
+    fn test_varint_rows() {
+        let small = row(1_u8);
+        let large = row("Rinha de Backend".repeat(20));
+        assert!(large.len() > 128);
+
+        let mut page = Page::<512>::new();
+        page.insert(&small).unwrap();
+        page.insert(&large).unwrap();
+        assert_eq!(1, page.as_ref()[0] >> 1);
+
+        let mut data = page.as_ref().to_vec();
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let page = Page::<512>::from_bytes(data);
+        assert!(page.is_intact(0));
+        assert!(page.is_intact(1));
+        assert_eq!(
+            vec![&small[..], &large[..]],
+            page.rows().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_varint_fits_more_than_fixed() {
+        let max = SizePrefix::Varint.max_row_size(64, CHECKSUM);
+        assert_eq!(64 - 1 - CHECKSUM, max);
+        assert_eq!(max - 7, SizePrefix::Fixed.max_row_size(64, CHECKSUM));
+
+        let mut page = Page::<64>::new();
+        page.insert(&vec![1; max]).unwrap();
+        assert_eq!(
+            vec![&[1; 64 - 1 - CHECKSUM][..]],
+            page.rows().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_fixed_prefix_page() {
+        let mut page = Page::<1024>::with_layout(RowLayout {
+            prefix: SizePrefix::Fixed,
+            ..RowLayout::default()
+        });
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        page.insert(&row(3)).unwrap();
+        assert_eq!(
+            row(1).len() as u64,
+            u64::from_be_bytes(page.as_ref()[0..8].try_into().unwrap())
+        );
+        assert!(page.delete(1));
+
+        let page = Page::<1024>::from_bytes_with_layout(page.as_ref().to_vec(), page.layout);
+        assert_eq!(1, page.available_rows());
+        let rows = page
+            .rows()
+            .map(|row| bitcode::deserialize::<i32>(row).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 3], rows);
+    }
+
+    #[test]
+    fn test_get_row() {
+        let mut page = Page::<1024>::new();
+        assert_eq!(None, page.get(0));
+
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        page.insert(&row(3)).unwrap();
+        assert_eq!(Some(&row(1)[..]), page.get(0));
+        assert_eq!(Some(&row(3)[..]), page.get(2));
+        assert_eq!(None, page.get(3));
+        assert_eq!(None, page.get(DEFAULT_PAGE_SIZE / 1024));
+        assert_eq!(None, page.get(usize::MAX / 1024));
+
+        assert!(page.delete(1));
+        assert_eq!(None, page.get(1));
+
+        let mut data = page.as_ref().to_vec();
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let page = Page::<1024>::from_bytes(data);
+        assert_eq!(Some(&row(3)[..]), page.get(2));
+        assert_eq!(None, page.get(3));
+    }
+
+    #[test]
+    fn test_remove_row() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+        page.insert(&row(3)).unwrap();
+
+        assert!(page.remove(1));
+        assert!(!page.remove(2));
+        assert_eq!(Some(&row(1)[..]), page.get(0));
+        assert_eq!(Some(&row(3)[..]), page.get(1));
+        assert_eq!(None, page.get(2));
+        assert_eq!(2, page.available_rows());
+        assert!(page.as_ref()[2048..].iter().all(|byte| *byte == 0));
+
+        let mut data = page.as_ref().to_vec();
+        data.resize(DEFAULT_PAGE_SIZE, 0);
+        let mut page = Page::<1024>::from_bytes(data);
+        assert_eq!(2, page.available_rows());
+
+        page.insert(&row(4)).unwrap();
+        assert!(page.remove(0));
+        let rows = page
+            .rows()
+            .map(|row| bitcode::deserialize::<i32>(row).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![3, 4], rows);
+        assert_eq!(2, page.available_rows());
+    }
+
+    #[test]
+    fn test_update_row() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+        page.insert(&row(2)).unwrap();
+
+        page.update(0, &row("updated")).unwrap();
+        assert_eq!(Some(&row("updated")[..]), page.get(0));
+        assert_eq!(Some(&row(2)[..]), page.get(1));
+        assert_eq!(2, page.available_rows());
+
+        assert!(matches!(page.update(2, &row(3)), Err(Error::NotFound)));
+        page.delete(1);
+        assert!(matches!(page.update(1, &row(3)), Err(Error::NotFound)));
+        assert_eq!(None, page.get(1));
+    }
+
+    #[test]
+    fn test_update_oversized_row() {
+        let mut page = Page::<1024>::new();
+        page.insert(&row(1)).unwrap();
+
+        let max = RowLayout::default().max_row_size(1024);
+        assert!(matches!(
+            page.update(0, &vec![1; max + 1]),
+            Err(Error::RowTooLarge { size, max: limit }) if size == max + 1 && limit == max
+        ));
+        assert_eq!(Some(&row(1)[..]), page.get(0));
+    }
+
+    #[test]
+    fn test_free_space() {
+        let mut page = Page::<1024>::new();
+        assert_eq!(DEFAULT_PAGE_SIZE, page.free_bytes());
+        assert!(!page.is_full());
+
+        page.insert(&row(1)).unwrap();
+        assert_eq!(DEFAULT_PAGE_SIZE - 1024, page.free_bytes());
+        assert!(!page.is_full());
+
+        for i in 2..=4 {
+            page.insert(&row(i)).unwrap();
+        }
+        assert_eq!(0, page.free_bytes());
+        assert!(page.is_full());
+
+        let page = Page::<1024>::from_bytes(page.as_ref().to_vec());
+        assert_eq!(0, page.free_bytes());
+        assert!(page.is_full());
+    }
+
+    #[test]
+    fn test_search_by_key() {
+        let mut page = Page::<64>::new();
+        for timestamp in [10_u64, 20, 30, 40, 50, 60] {
+            page.insert(&row(timestamp)).unwrap();
+        }
+        let probes = std::cell::Cell::new(0);
+        let key = |row: &[u8]| {
+            probes.set(probes.get() + 1);
+            bitcode::deserialize::<u64>(row).unwrap()
+        };
+
+        assert_eq!(Ok(3), page.search_by(&40, key));
+        assert!(probes.get() < 6);
+        assert_eq!(Err(2), page.search_by(&25, key));
+        assert_eq!(Err(0), page.search_by(&5, key));
+        assert_eq!(Err(6), page.search_by(&70, key));
+
+        page.delete(2);
+        page.delete(3);
+        assert_eq!(Ok(4), page.search_by(&50, key));
+        assert_eq!(Ok(1), page.search_by(&20, key));
+        assert!(page.search_by(&40, key).is_err());
+    }
 }
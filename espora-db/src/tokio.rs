@@ -1,184 +1,588 @@
 use std::{
+    borrow::Borrow,
+    fs, iter,
     marker::PhantomData,
-    os::fd::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 
 use async_stream::stream;
-use futures::{stream, Stream, StreamExt};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
     fs::{File, OpenOptions},
-    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::oneshot,
-    task,
+    io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    task, time,
 };
 
 use crate::{
     builder::Builder,
-    lock::LockHandle,
-    page::{Page, PAGE_SIZE},
-    DbResult,
+    codec::{Bitcode, Codec},
+    decode_page,
+    format::{PageFormat, HEADER_LEN},
+    lock::{self, LockHandle, LockMode},
+    page::{Page, RowLayout, DEFAULT_PAGE_SIZE},
+    DbResult, Error, MAX_LOCK_BACKOFF,
 };
 
-pub struct Db<T, const ROW_SIZE: usize> {
-    current_page: Page<ROW_SIZE>,
+pub struct Db<
+    T,
+    const ROW_SIZE: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    C: Codec = Bitcode,
+> {
+    current_page: Page<ROW_SIZE, PAGE_SIZE>,
+    format: PageFormat<PAGE_SIZE>,
+    path: PathBuf,
     reader: File,
     writer: File,
     last_sync: Instant,
     pub(crate) sync_writes: Option<Duration>,
+    read_only: bool,
+    group_commit: Option<(GroupCommit, Arc<fs::File>)>,
     data: PhantomData<T>,
+    codec: PhantomData<C>,
 }
 
-impl<const ROW_SIZE: usize, T: Serialize + DeserializeOwned> Db<T, ROW_SIZE> {
+impl<const ROW_SIZE: usize, const PAGE_SIZE: usize, T: Serialize + DeserializeOwned, C: Codec>
+    Db<T, ROW_SIZE, PAGE_SIZE, C>
+{
     pub fn builder() -> Builder {
         Builder::default()
     }
 
     pub async fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path, Builder::default()).await
+    }
+
+    /// Opens an existing database for reading only. Every write fails with [`Error::ReadOnly`].
+    pub async fn open_read_only(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path, Builder::default().read_only(true)).await
+    }
+
+    pub(crate) async fn open(path: impl AsRef<Path>, builder: Builder) -> io::Result<Self> {
         let mut file = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
+            .write(!builder.read_only)
+            .create(builder.create_if_missing && !builder.read_only)
+            .truncate(false)
             .open(&path)
             .await?;
 
-        let current_page = if file
-            .seek(io::SeekFrom::End(-(PAGE_SIZE as i64)))
-            .await
-            .is_ok()
-        {
-            let mut buf = vec![0; PAGE_SIZE];
-            file.read_exact(&mut buf).await?;
-            file.seek(io::SeekFrom::End(-(PAGE_SIZE as i64))).await?;
-            Page::from_bytes(buf)
+        let mut format = PageFormat {
+            #[cfg(feature = "encryption")]
+            cipher: builder.cipher,
+            layout: RowLayout::default(),
+            headerless: false,
+        };
+
+        let len = file.metadata().await?.len();
+        if len == 0 && !builder.read_only {
+            file.write_all(&format.header::<ROW_SIZE>()).await?;
+            file.flush().await?;
         } else {
-            file.seek(io::SeekFrom::End(0)).await?;
-            Page::new()
+            // Headerless files are recognized by their first row
+            let mut header = vec![0; HEADER_LEN.max(ROW_SIZE).min(len as usize)];
+            file.read_exact(&mut header).await?;
+            format.check_header::<ROW_SIZE>(&header)?;
+        }
+
+        if !builder.read_only && file.metadata().await?.len() < builder.preallocate {
+            file.set_len(builder.preallocate).await?;
+        }
+
+        let current_page = last_page(&mut file, &format).await?;
+
+        let group_commit = match builder.group_commit {
+            Some(group_commit) => Some((
+                group_commit,
+                Arc::new(file.try_clone().await?.into_std().await),
+            )),
+            None => None,
         };
 
         Ok(Self {
             current_page,
+            format,
+            path: path.as_ref().to_path_buf(),
             reader: File::open(&path).await?,
             writer: file,
             last_sync: Instant::now(),
-            sync_writes: Some(Duration::from_secs(0)),
+            sync_writes: builder.sync_writes,
+            read_only: builder.read_only,
+            group_commit,
             data: PhantomData,
+            codec: PhantomData,
         })
     }
 
-    pub async fn insert(&mut self, row: T) -> DbResult<()> {
-        self.current_page.insert(row)?;
+    /// Inserts a row, returning its byte offset in the file.
+    pub async fn insert(&mut self, row: T) -> DbResult<u64> {
+        let offset =
+            self.current_page_offset().await? + (PAGE_SIZE - self.current_page.free_bytes()) as u64;
+        self.insert_many(iter::once(row)).await?;
+        Ok(offset)
+    }
 
-        self.writer
-            .write_all(
-                &[
-                    self.current_page.as_ref(),
-                    &vec![0; PAGE_SIZE - self.current_page.len()],
-                ]
-                .concat(),
-            )
-            .await?;
+    /// Inserts all the rows, writing each page once it is full and syncing only at the end.
+    ///
+    /// If a row fails to serialize, the rows before it are still written.
+    pub async fn insert_many(&mut self, rows: impl IntoIterator<Item = T>) -> DbResult<()> {
+        self.check_writable()?;
 
-        match self.sync_writes {
-            Some(interval) if self.last_sync.elapsed() > interval => {
-                self.writer.sync_data().await?;
-                self.last_sync = Instant::now();
+        let mut pending = false;
+        let mut result = Ok(());
+
+        for row in rows {
+            if let Err(err) = C::serialize(&row).and_then(|row| self.current_page.insert(&row)) {
+                result = Err(err);
+                break;
+            }
+            pending = true;
+
+            if self.current_page.is_full() {
+                self.writer
+                    .write_all(&self.format.seal(&self.current_page))
+                    .await?;
+                self.current_page = self.format.new_page();
+                pending = false;
             }
-            _ => {}
         }
 
-        if self.current_page.available_rows() == 0 {
-            self.current_page = Page::new();
-        } else {
+        // The page being filled is written too, and rewritten in place by the next insert
+        if pending {
+            self.writer
+                .write_all(&self.format.seal(&self.current_page))
+                .await?;
             self.writer
-                .seek(io::SeekFrom::End(-(PAGE_SIZE as i64)))
+                .seek(io::SeekFrom::Current(-(self.format.stride() as i64)))
                 .await?;
         }
 
+        self.sync_if_needed().await?;
+
+        result
+    }
+
+    /// Removes every row, truncating the file back to its header.
+    pub async fn clear(&mut self) -> DbResult<()> {
+        self.check_writable()?;
+
+        let header_size = self.format.header_size();
+        self.writer.set_len(header_size).await?;
+        self.writer.seek(io::SeekFrom::Start(header_size)).await?;
+        self.current_page = self.format.new_page();
+        self.sync_if_needed().await
+    }
+
+    /// Waits for every write to reach the file.
+    pub async fn flush(&mut self) -> DbResult<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Flushes and syncs the file to disk, regardless of the `sync_writes` setting.
+    pub async fn sync(&mut self) -> DbResult<()> {
+        self.writer.flush().await?;
+        self.writer.sync_data().await?;
+        self.last_sync = Instant::now();
         Ok(())
     }
 
+    fn check_writable(&self) -> DbResult<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    async fn sync_if_needed(&mut self) -> DbResult<()> {
+        if let Some((group_commit, file)) = &self.group_commit {
+            self.writer.flush().await?;
+            group_commit.sync(file.clone()).await?;
+            self.last_sync = Instant::now();
+            return Ok(());
+        }
+
+        match self.sync_writes {
+            Some(interval) if self.last_sync.elapsed() > interval => self.sync().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Blocks until no other handle holds the write lock of the file. The last page is reloaded
+    /// once the lock is acquired, as other handles may have written to the file meanwhile.
     pub async fn lock_writes(&mut self) -> DbResult<LockHandle> {
-        let (tx, rx) = oneshot::channel();
-        let fd = self.writer.as_raw_fd();
-        task::spawn_blocking(move || match unsafe { libc::flock(fd, libc::LOCK_EX) } {
-            0 => tx.send(Ok(LockHandle { fd })),
-            _ => tx.send(Err(io::Error::new(
-                io::ErrorKind::Other,
-                "couldn't acquire lock",
-            ))),
-        });
-        Ok(rx.await.unwrap()?)
+        let lock = poll_lock(&self.writer, LockMode::Exclusive).await?;
+
+        self.current_page = last_page(&mut self.writer, &self.format).await?;
+
+        Ok(lock)
     }
 
-    fn pages(&mut self) -> impl Stream<Item = Page<ROW_SIZE>> + '_ {
-        let mut cursor = 0;
-        stream! {
-            loop {
-                let offset = (cursor * PAGE_SIZE) as u64;
+    /// Waits until no other handle holds the write lock, then shares the lock with other readers,
+    /// keeping writers out until the handle is dropped.
+    ///
+    /// To write, upgrade with [`Db::lock_writes`] on the same `Db`. The upgrade isn't atomic, so
+    /// rows read under the shared lock may be stale by then, and dropping either handle releases
+    /// the lock. Windows can't upgrade locks at all, so drop the shared lock first there.
+    pub async fn lock_reads(&mut self) -> DbResult<LockHandle> {
+        Ok(poll_lock(&self.writer, LockMode::Shared).await?)
+    }
 
-                if self.reader.seek(io::SeekFrom::Start(offset)).await.is_err() {
-                    break;
-                }
+    /// Same as [`Db::lock_writes`], but returns `None` instead of waiting when another handle
+    /// holds the lock.
+    pub async fn try_lock_writes(&mut self) -> DbResult<Option<LockHandle>> {
+        let Some(lock) = lock::try_lock(lock::raw_file(&self.writer), LockMode::Exclusive)? else {
+            return Ok(None);
+        };
 
-                let mut buf = vec![0; PAGE_SIZE];
-                cursor += 1;
-                match self.reader.read_exact(&mut buf).await {
-                    Ok(n) if n > 0 => yield Page::<ROW_SIZE>::from_bytes(buf),
-                    _ => break,
-                }
+        self.current_page = last_page(&mut self.writer, &self.format).await?;
+
+        Ok(Some(lock))
+    }
+
+    /// Same as [`Db::lock_writes`], but gives up with [`Error::WouldBlock`] after the timeout.
+    pub async fn lock_writes_timeout(&mut self, timeout: Duration) -> DbResult<LockHandle> {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            if let Some(lock) = self.try_lock_writes().await? {
+                return Ok(lock);
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(Error::WouldBlock);
             }
+
+            time::sleep(backoff.min(timeout - elapsed)).await;
+            backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+        }
+    }
+
+    /// Copies every complete page to a new file at `dest`, which can be opened as a database of
+    /// its own. Writes from other handles are held off by the write lock while copying, so don't
+    /// call it while holding [`Db::lock_writes`] from this handle, as its lock is released here.
+    pub async fn backup(&mut self, dest: impl AsRef<Path>) -> DbResult<()> {
+        let _lock = self.lock_writes().await?;
+        self.flush().await?;
+
+        let end = self
+            .format
+            .last_page_boundary(self.reader.metadata().await?.len());
+        self.reader.seek(io::SeekFrom::Start(0)).await?;
+
+        let mut dest = File::create(dest).await?;
+        io::copy(&mut (&mut self.reader).take(end), &mut dest).await?;
+        dest.sync_all().await?;
+
+        Ok(())
+    }
+
+    /// Offset of the page currently being filled by inserts.
+    async fn current_page_offset(&mut self) -> io::Result<u64> {
+        Ok(self
+            .format
+            .logical_offset(self.writer.stream_position().await?))
+    }
+
+    /// Reads the oldest live row.
+    pub async fn first(&mut self) -> DbResult<Option<T>> {
+        pin!(self.rows()).next().await.transpose()
+    }
+
+    /// Reads the newest live row, straight from the page being filled when it has any. Rows
+    /// written by other handles are only seen once [`Db::lock_writes`] reloads that page.
+    pub async fn last(&mut self) -> DbResult<Option<T>> {
+        let offset = self.current_page_offset().await?;
+        if let Some(row) = decode_page(offset, &self.current_page, C::deserialize).pop() {
+            return row.map(|(_, row)| Some(row));
         }
+
+        pin!(self.rows_reverse()).next().await.transpose()
+    }
+
+    /// Size of the file on disk, including the header and any preallocated space.
+    pub async fn file_len(&self) -> io::Result<u64> {
+        Ok(self.reader.metadata().await?.len())
+    }
+
+    /// Number of pages in the file, from its size alone. Preallocated pages count too.
+    pub async fn page_count(&self) -> io::Result<u64> {
+        Ok(self.format.page_count(self.file_len().await?))
+    }
+
+    /// Counts the live rows in the database without deserializing them.
+    pub async fn count(&mut self) -> DbResult<u64> {
+        self.pages()
+            .map(|page| page.map(|(_, page)| page.row_count() as u64))
+            .try_fold(0, |count, rows| async move { Ok(count + rows) })
+            .await
     }
 
-    fn pages_reverse(&mut self) -> impl Stream<Item = Page<ROW_SIZE>> + '_ {
-        let mut cursor = 1;
+    fn pages(&mut self) -> impl Stream<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> + '_ {
+        read_pages(&mut self.reader, &self.format)
+    }
+
+    fn pages_reverse(
+        &mut self,
+    ) -> impl Stream<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> + '_ {
+        let stride = self.format.stride();
         stream! {
-            loop {
-                let offset = (cursor * PAGE_SIZE) as i64;
+            let mut end = match self.reader.metadata().await {
+                Ok(metadata) => self.format.last_page_boundary(metadata.len()),
+                Err(_) => 0,
+            };
+            let mut written = false;
+            let mut buf = vec![0; stride];
 
-                if self.reader.seek(io::SeekFrom::End(-offset)).await.is_err() {
+            while let Some(offset) = self.format.previous_page(end) {
+                if self.reader.seek(io::SeekFrom::Start(offset)).await.is_err() {
                     break;
                 }
 
-                let mut buf = vec![0; PAGE_SIZE];
-                cursor += 1;
+                end = offset;
                 match self.reader.read_exact(&mut buf).await {
-                    Ok(n) if n > 0 => yield Page::<ROW_SIZE>::from_bytes(buf),
+                    Ok(n) if n > 0 => {}
                     _ => break,
                 }
+
+                match self.format.open(&buf) {
+                    // Preallocated space comes before the last written page
+                    Ok(page) if page.is_unused() && !written => {}
+                    page => {
+                        written = true;
+                        yield page
+                            .map(|page| (self.format.logical_offset(offset), page))
+                            .map_err(Error::from)
+                    }
+                }
             }
         }
     }
 
     pub fn rows(&mut self) -> impl Stream<Item = DbResult<T>> + '_ {
+        self.rows_with_offset().map(|row| row.map(|(_, row)| row))
+    }
+
+    pub fn rows_reverse(&mut self) -> impl Stream<Item = DbResult<T>> + '_ {
+        self.rows_reverse_with_offset()
+            .map(|row| row.map(|(_, row)| row))
+    }
+
+    /// Same as [`Db::rows`], but also yields the absolute byte offset of each row in the file.
+    pub fn rows_with_offset(&mut self) -> impl Stream<Item = DbResult<(u64, T)>> + '_ {
         self.pages().flat_map(|page| {
-            stream::iter(
-                page.rows()
-                    .map(|row| bitcode::deserialize(row).map_err(|err| err.into()))
-                    .collect::<Vec<_>>(),
-            )
+            stream::iter(match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize),
+                Err(err) => vec![Err(err)],
+            })
         })
     }
 
-    pub fn rows_reverse(&mut self) -> impl Stream<Item = DbResult<T>> + '_ {
+    /// Same as [`Db::rows`], but the stream owns its own handle to the file instead of borrowing
+    /// the database, so it can be moved to another task. The file is opened again rather than
+    /// cloned, as a cloned handle shares its position with the reads of the database.
+    pub async fn scan(&self) -> io::Result<impl Stream<Item = DbResult<T>> + Send + 'static>
+    where
+        T: Send + 'static,
+        C: 'static,
+    {
+        let reader = File::open(&self.path).await?;
+        let format = self.format.clone();
+        Ok(
+            read_pages::<ROW_SIZE, PAGE_SIZE>(reader, format).flat_map(|page| {
+                stream::iter(match page {
+                    Ok((offset, page)) => decode_page(offset, &page, C::deserialize),
+                    Err(err) => vec![Err(err)],
+                })
+                .map(|row| row.map(|(_, row)| row))
+            }),
+        )
+    }
+
+    /// Same as [`Db::rows_reverse`], but also yields the absolute byte offset of each row in the
+    /// file.
+    pub fn rows_reverse_with_offset(&mut self) -> impl Stream<Item = DbResult<(u64, T)>> + '_ {
         self.pages_reverse().flat_map(|page| {
-            stream::iter(
-                page.rows()
-                    .map(|row| bitcode::deserialize(row).map_err(|err| err.into()))
-                    .collect::<Vec<_>>()
+            stream::iter(match page {
+                Ok((offset, page)) => decode_page(offset, &page, C::deserialize)
                     .into_iter()
-                    .rev(),
-            )
+                    .rev()
+                    .collect(),
+                Err(err) => vec![Err(err)],
+            })
         })
     }
 }
 
+/// Syncs every database built from the same [`Builder`] together. The first write waiting for a
+/// sync opens a window, and once it elapses each file written during it is synced a single time.
+#[derive(Debug, Clone)]
+pub(crate) struct GroupCommit {
+    window: Duration,
+    commits: Arc<OnceLock<mpsc::UnboundedSender<Commit>>>,
+}
+
+type Commit = (Arc<fs::File>, oneshot::Sender<io::Result<()>>);
+
+impl GroupCommit {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            commits: Arc::default(),
+        }
+    }
+
+    /// Waits for the next shared sync of the file.
+    async fn sync(&self, file: Arc<fs::File>) -> io::Result<()> {
+        let commits = self.commits.get_or_init(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(Self::run(self.window, rx));
+            tx
+        });
+
+        let (done, synced) = oneshot::channel();
+        commits
+            .send((file, done))
+            .map_err(|_| io::Error::other("group commit stopped"))?;
+        synced
+            .await
+            .map_err(|_| io::Error::other("group commit stopped"))?
+    }
+
+    async fn run(window: Duration, mut commits: mpsc::UnboundedReceiver<Commit>) {
+        while let Some(commit) = commits.recv().await {
+            time::sleep(window).await;
+
+            let mut pending = vec![commit];
+            while let Ok(commit) = commits.try_recv() {
+                pending.push(commit);
+            }
+
+            let mut files: Vec<Arc<fs::File>> = Vec::new();
+            for (file, _) in &pending {
+                if !files.iter().any(|synced| Arc::ptr_eq(synced, file)) {
+                    files.push(file.clone());
+                }
+            }
+
+            let results = task::spawn_blocking(move || {
+                files
+                    .into_iter()
+                    .map(|file| {
+                        let result = file.sync_data().map_err(|err| err.kind());
+                        (file, result)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+
+            for (file, done) in pending {
+                let result = match results
+                    .iter()
+                    .find(|(synced, _)| Arc::ptr_eq(synced, &file))
+                {
+                    Some((_, Ok(()))) => Ok(()),
+                    Some((_, Err(kind))) => Err(io::Error::new(*kind, "couldn't sync file")),
+                    None => Err(io::Error::other("couldn't sync file")),
+                };
+                done.send(result).ok();
+            }
+        }
+    }
+}
+
+/// Reads every page from the start of the file, stopping at the first one never written.
+fn read_pages<const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+    mut reader: impl AsyncRead + AsyncSeek + Unpin,
+    format: impl Borrow<PageFormat<PAGE_SIZE>>,
+) -> impl Stream<Item = DbResult<(u64, Page<ROW_SIZE, PAGE_SIZE>)>> {
+    stream! {
+        let format = format.borrow();
+        let stride = format.stride();
+        let mut cursor = 0;
+        let mut buf = vec![0; stride];
+
+        loop {
+            let offset = format.physical_offset((cursor * PAGE_SIZE) as u64);
+
+            if reader.seek(io::SeekFrom::Start(offset)).await.is_err() {
+                break;
+            }
+
+            cursor += 1;
+            match reader.read_exact(&mut buf).await {
+                Ok(n) if n > 0 => {}
+                _ => break,
+            }
+
+            match format.open(&buf) {
+                // Only preallocated space is left past the first unused page
+                Ok(page) if page.is_unused() => break,
+                page => yield page
+                    .map(|page| (format.logical_offset(offset), page))
+                    .map_err(Error::from),
+            }
+        }
+    }
+}
+
+/// Retries the lock instead of blocking a thread on it, so a dropped future never leaves behind a
+/// thread that acquires the lock later, only to release it along with any taken since.
+async fn poll_lock(file: &File, mode: LockMode) -> io::Result<LockHandle> {
+    let file = lock::raw_file(file);
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        if let Some(lock) = lock::try_lock(file, mode)? {
+            return Ok(lock);
+        }
+
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+    }
+}
+
+/// Reads the last page of the file, positioning it to be rewritten when the page still has room.
+/// Otherwise the file is positioned at its end and a new page is returned.
+async fn last_page<const ROW_SIZE: usize, const PAGE_SIZE: usize>(
+    file: &mut File,
+    format: &PageFormat<PAGE_SIZE>,
+) -> io::Result<Page<ROW_SIZE, PAGE_SIZE>> {
+    // A crash in the middle of a write may leave a trailing partial page behind, which is ignored
+    // and overwritten by the next insert
+    let mut end = format.last_page_boundary(file.metadata().await?.len());
+
+    while let Some(offset) = format.previous_page(end) {
+        let mut buf = vec![0; format.stride()];
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        file.read_exact(&mut buf).await?;
+
+        // Preallocated pages are filled before growing the file
+        let page = format.open(&buf)?;
+        if page.is_unused() {
+            end = offset;
+            continue;
+        }
+
+        if !page.is_full() {
+            file.seek(io::SeekFrom::Start(offset)).await?;
+            return Ok(page);
+        }
+        break;
+    }
+
+    file.seek(io::SeekFrom::Start(end)).await?;
+    Ok(format.new_page())
+}
+
 #[cfg(test)]
 mod tests {
-    use futures::TryStreamExt;
     use tempfile::tempdir;
 
     use super::*;
@@ -216,4 +620,365 @@ mod tests {
         let rows = db.rows_reverse().try_collect::<Vec<_>>().await.unwrap();
         assert_eq!(vec![5, 4, 3, 2, 1], rows);
     }
+
+    #[tokio::test]
+    async fn test_db_rows_with_offset() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 2048>::from_path(tmp.path().join("test.espora"))
+            .await
+            .unwrap();
+
+        assert_eq!(0, db.insert(1).await.unwrap());
+        assert_eq!(2048, db.insert(2).await.unwrap());
+        assert_eq!(4096, db.insert(3).await.unwrap());
+
+        let rows = db.rows_with_offset().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![(0, 1), (2048, 2), (4096, 3)], rows);
+
+        let rows = db
+            .rows_reverse_with_offset()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(vec![(4096, 3), (2048, 2), (0, 1)], rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_count() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 1024>::from_path(tmp.path().join("test.espora"))
+            .await
+            .unwrap();
+
+        for i in 0..10 {
+            db.insert(i).await.unwrap();
+        }
+
+        assert_eq!(10, db.count().await.unwrap());
+        assert_eq!(db.rows().count().await as u64, db.count().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_db_file_len_and_page_count() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 1024>::from_path(tmp.path().join("test.espora"))
+            .await
+            .unwrap();
+        assert_eq!(0, db.page_count().await.unwrap());
+
+        db.insert_many(1..=5).await.unwrap();
+        assert_eq!(3 * 4096, db.file_len().await.unwrap());
+        assert_eq!(2, db.page_count().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_db_reopen_fills_last_page() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 1024>::from_path(&path).await.unwrap();
+        db.insert(1).await.unwrap();
+        db.insert(2).await.unwrap();
+
+        let mut db = Db::<i64, 1024>::from_path(&path).await.unwrap();
+        db.insert(3).await.unwrap();
+        assert_eq!(
+            2 * DEFAULT_PAGE_SIZE as u64,
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![1, 2, 3], rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_ignores_trailing_partial_page() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let mut db = Db::<i64, 2048>::from_path(&path).await.unwrap();
+
+        db.insert(1).await.unwrap();
+        db.insert(2).await.unwrap();
+        db.insert(3).await.unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(&[1, 2, 3, 4, 5]).await.unwrap();
+
+        let rows = db.rows_reverse().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![3, 2, 1], rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_insert_many() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        db.insert(-1).await.unwrap();
+        db.insert_many(0..1000).await.unwrap();
+        db.insert(1000).await.unwrap();
+
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!((-1..=1000).collect::<Vec<_>>(), rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_sync() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::builder()
+            .sync_writes(false)
+            .build_tokio::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .await
+            .unwrap();
+        db.insert_many(0..100).await.unwrap();
+        db.flush().await.unwrap();
+        db.sync().await.unwrap();
+        drop(db);
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_clear() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        db.insert_many(0..100).await.unwrap();
+        db.clear().await.unwrap();
+
+        assert_eq!(0, db.rows().count().await);
+
+        db.insert(1).await.unwrap();
+        db.insert(2).await.unwrap();
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![1, 2], rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_lock_writes() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        let mut other = Db::<i64, 64>::from_path(&path).await.unwrap();
+
+        let lock = db.lock_writes().await.unwrap();
+        let blocked = time::timeout(Duration::from_millis(100), other.lock_writes()).await;
+        assert!(blocked.is_err());
+
+        db.insert(1).await.unwrap();
+        drop(lock);
+
+        let _lock = other.lock_writes().await.unwrap();
+        other.insert(2).await.unwrap();
+
+        // The attempt that timed out must not take the lock and release it on its own
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(db.try_lock_writes().await.unwrap().is_none());
+
+        let rows = other.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![1, 2], rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_try_lock_writes() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        let mut other = Db::<i64, 64>::from_path(&path).await.unwrap();
+
+        let lock = db.try_lock_writes().await.unwrap();
+        assert!(lock.is_some());
+        assert!(other.try_lock_writes().await.unwrap().is_none());
+        assert!(other
+            .lock_writes_timeout(Duration::from_millis(20))
+            .await
+            .is_err_and(|err| matches!(err, Error::WouldBlock)));
+
+        drop(lock);
+        assert!(other.try_lock_writes().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_db_lock_reads() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        let mut reader = Db::<i64, 64>::from_path(&path).await.unwrap();
+        let mut writer = Db::<i64, 64>::from_path(&path).await.unwrap();
+
+        let lock = db.lock_reads().await.unwrap();
+        let other_lock = reader.lock_reads().await.unwrap();
+        assert!(writer.try_lock_writes().await.unwrap().is_none());
+
+        drop((lock, other_lock));
+        assert!(writer.try_lock_writes().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_db_sync_write_interval() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::builder()
+            .sync_write_interval(Duration::from_millis(50))
+            .build_tokio::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .await
+            .unwrap();
+        let opened_at = db.last_sync;
+
+        db.insert(1).await.unwrap();
+        assert_eq!(opened_at, db.last_sync);
+
+        time::sleep(Duration::from_millis(60)).await;
+        db.insert(2).await.unwrap();
+        assert!(db.last_sync > opened_at);
+    }
+
+    #[tokio::test]
+    async fn test_db_read_only() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        db.insert_many(0..10).await.unwrap();
+
+        let mut db = Db::<i64, 64>::open_read_only(&path).await.unwrap();
+        assert!(matches!(db.insert(10).await, Err(Error::ReadOnly)));
+        assert!(matches!(db.clear().await, Err(Error::ReadOnly)));
+
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!((0..10).collect::<Vec<_>>(), rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_preallocate() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 1024>::builder()
+            .preallocate(1024 * 1024)
+            .build_tokio::<i64, 1024, DEFAULT_PAGE_SIZE, Bitcode>(&path)
+            .await
+            .unwrap();
+        db.insert_many(1..=5).await.unwrap();
+
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], rows);
+        let rows = db.rows_reverse().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(vec![5, 4, 3, 2, 1], rows);
+        assert_eq!(1024 * 1024, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_db_group_commit() {
+        let tmp = tempdir().unwrap();
+        let builder = Db::<i64, 64>::builder().group_commit(Duration::from_millis(5));
+
+        let mut dbs = Vec::new();
+        for account in 0..4 {
+            let db = builder
+                .clone()
+                .build_tokio::<i64, 64, DEFAULT_PAGE_SIZE, Bitcode>(
+                    tmp.path().join(format!("{account}.espora")),
+                )
+                .await
+                .unwrap();
+            dbs.push(Arc::new(tokio::sync::Mutex::new(db)));
+        }
+
+        let inserts = (0..100)
+            .map(|i| {
+                let db = dbs[i % dbs.len()].clone();
+                tokio::spawn(async move { db.lock().await.insert(i as i64).await })
+            })
+            .collect::<Vec<_>>();
+        for insert in inserts {
+            insert.await.unwrap().unwrap();
+        }
+        drop(dbs);
+
+        let mut rows = Vec::new();
+        for account in 0..4 {
+            let mut db = Db::<i64, 64>::from_path(tmp.path().join(format!("{account}.espora")))
+                .await
+                .unwrap();
+            rows.extend(db.rows().try_collect::<Vec<_>>().await.unwrap());
+        }
+        rows.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), rows);
+    }
+
+    #[tokio::test]
+    async fn test_db_first_and_last() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+
+        let mut db = Db::<i64, 1024>::from_path(&path).await.unwrap();
+        assert_eq!(None, db.first().await.unwrap());
+        assert_eq!(None, db.last().await.unwrap());
+
+        db.insert_many(1..=5).await.unwrap();
+        assert_eq!(Some(1), db.first().await.unwrap());
+        assert_eq!(Some(5), db.last().await.unwrap());
+
+        let mut db = Db::<i64, 1024>::from_path(&path).await.unwrap();
+        assert_eq!(Some(5), db.last().await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_db_scan() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 64>::from_path(tmp.path().join("test.espora"))
+            .await
+            .unwrap();
+        db.insert_many(0..100).await.unwrap();
+
+        let rows = db.scan().await.unwrap();
+        let rows = tokio::spawn(async move { rows.try_collect::<Vec<_>>().await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), rows);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_db_scan_alongside_reads() {
+        let tmp = tempdir().unwrap();
+        let mut db = Db::<i64, 64>::from_path(tmp.path().join("test.espora"))
+            .await
+            .unwrap();
+        db.insert_many(0..2000).await.unwrap();
+
+        for _ in 0..10 {
+            let rows = db.scan().await.unwrap();
+            let scan = tokio::spawn(async move { rows.try_collect::<Vec<_>>().await });
+            let mut reversed = db.rows_reverse().try_collect::<Vec<_>>().await.unwrap();
+            reversed.reverse();
+            assert_eq!(reversed, scan.await.unwrap().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_db_backup() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("test.espora");
+        let backup_path = tmp.path().join("backup.espora");
+
+        let mut db = Db::<i64, 64>::from_path(&path).await.unwrap();
+        db.insert_many(0..100).await.unwrap();
+        db.backup(&backup_path).await.unwrap();
+
+        let mut backup = Db::<i64, 64>::from_path(&backup_path).await.unwrap();
+        let rows = db.rows().try_collect::<Vec<_>>().await.unwrap();
+        let backup_rows = backup.rows().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(rows, backup_rows);
+    }
 }
@@ -1,29 +1,91 @@
 use std::{
     collections::HashMap,
     env,
+    error::Error,
+    future::Future,
+    ops::{Deref, DerefMut},
     path::{Path as FilePath, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{
+        rejection::JsonRejection, DefaultBodyLimit, MatchedPath, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use espora_db::{tokio::Db, Error as DbError};
-use futures::{StreamExt, TryStreamExt};
-use rinha::{DateTime, Transaction, TransactionType};
+use espora_db::{tokio::Db, Error as DbError, LockHandle};
+use futures::{future, StreamExt, TryStreamExt};
+use rinha::{
+    AccountId, DateRange, DateTime, IdempotencyKeys, Pagination, Transaction,
+    IDEMPOTENCY_KEY_HEADER, METRICS, METRICS_CONTENT_TYPE,
+};
 use serde_json::json;
-use tokio::sync::Mutex;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
 
 type Balance = i64;
 
+/// Largest transaction body accepted, way over what a valid transaction takes.
+const MAX_TRANSACTION_SIZE: usize = 4 * 1024;
+
+/// How long `/ready` waits for the write lock of each account.
+const READY_TIMEOUT: Duration = Duration::from_millis(100);
+
+type AccountDb = Db<(Balance, Transaction), 128>;
+
 struct Account {
     limit: i64,
-    db: Db<(Balance, Transaction), 128>,
+    path: PathBuf,
+    writer: Mutex<Writer>,
+    /// Idle read-only handles to the db. Reads take one, or open another when they are all busy,
+    /// so they neither wait for the writes nor for each other.
+    readers: StdMutex<Vec<AccountDb>>,
+}
+
+/// The handle transactions are written through, one at a time.
+struct Writer {
+    db: AccountDb,
+    idempotency_keys: IdempotencyKeys<Result<Balance, &'static str>>,
+}
+
+/// A read-only handle borrowed from an account, given back when dropped. It holds the shared lock
+/// of the db meanwhile, so it never reads a page halfway through being written.
+struct Reader<'a> {
+    db: Option<AccountDb>,
+    lock: Option<LockHandle>,
+    pool: &'a StdMutex<Vec<AccountDb>>,
+}
+
+impl Deref for Reader<'_> {
+    type Target = AccountDb;
+
+    fn deref(&self) -> &Self::Target {
+        self.db.as_ref().expect("the db is only taken on drop")
+    }
+}
+
+impl DerefMut for Reader<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.db.as_mut().expect("the db is only taken on drop")
+    }
+}
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        // The lock is on the file of the db, so it has to go first
+        self.lock.take();
+        if let (Some(db), Ok(mut pool)) = (self.db.take(), self.pool.lock()) {
+            pool.push(db);
+        }
+    }
 }
 
 impl Account {
@@ -37,18 +99,70 @@ impl Account {
             .build_tokio(&path)
             .await?;
 
-        Ok(Account { limit, db })
+        Ok(Account {
+            limit,
+            path: path.as_ref().to_path_buf(),
+            writer: Mutex::new(Writer {
+                db,
+                idempotency_keys: IdempotencyKeys::default(),
+            }),
+            readers: StdMutex::new(Vec::new()),
+        })
     }
 
-    pub async fn transact(&mut self, transaction: Transaction) -> Result<Balance, &'static str> {
-        let lock = self
-            .db
+    async fn reader(&self) -> Result<Reader<'_>, DbError> {
+        let idle = self.readers.lock().ok().and_then(|mut pool| pool.pop());
+        let mut db = match idle {
+            Some(db) => db,
+            None => AccountDb::open_read_only(&self.path).await?,
+        };
+        let lock = db.lock_reads().await?;
+
+        Ok(Reader {
+            db: Some(db),
+            lock: Some(lock),
+            pool: &self.readers,
+        })
+    }
+
+    pub async fn transact(&self, transaction: Transaction) -> Result<Balance, &'static str> {
+        let mut writer = self.writer.lock().await;
+        Self::write(&mut writer.db, self.limit, transaction)
+            .await
+            .and_then(|result| result)
+    }
+
+    /// Same as [`Account::transact`], but a key seen recently by this process gets the result of
+    /// its first transaction back instead of applying another one.
+    pub async fn transact_once(
+        &self,
+        key: String,
+        transaction: Transaction,
+    ) -> Result<Balance, &'static str> {
+        let mut writer = self.writer.lock().await;
+        if let Some(result) = writer.idempotency_keys.get(&key) {
+            return *result;
+        }
+
+        // Failures of the db aren't kept, so retrying the key still gets the transaction applied
+        let result = Self::write(&mut writer.db, self.limit, transaction).await?;
+        writer.idempotency_keys.insert(key, result);
+        result
+    }
+
+    /// Applies the transaction to the last balance and stores it. Fails with the outer error when
+    /// the db does, and with the inner one when the transaction is rejected.
+    async fn write(
+        db: &mut AccountDb,
+        limit: i64,
+        transaction: Transaction,
+    ) -> Result<Result<Balance, &'static str>, &'static str> {
+        let lock = db
             .lock_writes()
             .await
             .map_err(|_| "Falha ao conseguir o lock do db")?;
 
-        let current_balance = self
-            .db
+        let current_balance = db
             .rows_reverse()
             .take(1)
             .try_collect::<Vec<_>>()
@@ -58,40 +172,136 @@ impl Account {
             .map(|(balance, _)| *balance)
             .unwrap_or(0);
 
-        let balance = match transaction.kind {
-            TransactionType::Credit => current_balance + transaction.value,
-            TransactionType::Debit => {
-                if current_balance + self.limit >= transaction.value {
-                    current_balance - transaction.value
-                } else {
-                    return Err("Não tem limite o suficiente");
-                }
-            }
+        let balance = match transaction.apply(current_balance, limit) {
+            Ok(balance) => balance,
+            Err(err) => return Ok(Err(err)),
         };
 
-        self.db
-            .insert((balance, transaction.clone()))
+        db.insert((balance, transaction.clone()))
             .await
             .map_err(|_| "Erro ao persistir no db")?;
 
         drop(lock);
 
-        Ok(balance)
+        Ok(Ok(balance))
     }
 
     pub async fn last_transactions(
-        &mut self,
+        &self,
         limit: usize,
     ) -> Result<Vec<(i64, Transaction)>, DbError> {
-        self.db
+        self.reader()
+            .await?
             .rows_reverse()
             .take(limit)
             .try_collect::<Vec<_>>()
             .await
     }
+
+    /// Up to `limit` rows older than the `before` cursor, newest first, along with the cursor of
+    /// the next page when there are older ones left and the current balance, read together.
+    pub async fn transactions_page(
+        &self,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<(Balance, Vec<(i64, Transaction)>, Option<u64>), DbError> {
+        let mut reader = self.reader().await?;
+        let balance = Self::last_balance(&mut reader).await?;
+        let mut rows = reader
+            .rows_reverse_with_offset()
+            .skip_while(|row| {
+                let skip = match (row, before) {
+                    (Ok((offset, _)), Some(before)) => *offset >= before,
+                    _ => false,
+                };
+                future::ready(skip)
+            })
+            .take(limit + 1)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(|(offset, _)| *offset)
+        } else {
+            None
+        };
+
+        Ok((
+            balance,
+            rows.into_iter().map(|(_, row)| row).collect(),
+            cursor,
+        ))
+    }
+
+    /// Transactions made within the range, newest first, along with the current balance, read
+    /// together. The scan stops at the first transaction older than the range, as they are stored
+    /// in the order they were made.
+    pub async fn transactions_between(
+        &self,
+        range: &DateRange,
+    ) -> Result<(Balance, Vec<Transaction>), DbError> {
+        let mut reader = self.reader().await?;
+        let balance = Self::last_balance(&mut reader).await?;
+        let transactions = reader
+            .rows_reverse()
+            .map_ok(|(_, transaction)| transaction)
+            .skip_while(|row| {
+                future::ready(matches!(row, Ok(txn) if range.is_after(&txn.created_at)))
+            })
+            .take_while(|row| {
+                future::ready(!matches!(row, Ok(txn) if range.is_before(&txn.created_at)))
+            })
+            .try_collect()
+            .await?;
+
+        Ok((balance, transactions))
+    }
+
+    async fn last_balance(db: &mut AccountDb) -> Result<Balance, DbError> {
+        Ok(db
+            .rows_reverse()
+            .take(1)
+            .try_collect::<Vec<_>>()
+            .await?
+            .first()
+            .map(|(balance, _)| *balance)
+            .unwrap_or_default())
+    }
 }
 
-type AppState = Arc<HashMap<u8, Mutex<Account>>>;
+type AppState = Arc<HashMap<AccountId, Account>>;
+
+/// An error response, with the message in a JSON body like `{"error": "..."}`.
+#[derive(Debug)]
+struct ApiError(StatusCode, &'static str);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let ApiError(status, message) = self;
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(_: DbError) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler do db")
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self(rejection.status(), "Transação muito grande"),
+            status => Self(status, "Transação inválida"),
+        }
+    }
+}
+
+const ACCOUNT_NOT_FOUND: ApiError = ApiError(StatusCode::NOT_FOUND, "Cliente não encontrado");
+
+const ACCOUNT_UNAVAILABLE: ApiError =
+    ApiError(StatusCode::SERVICE_UNAVAILABLE, "Conta indisponível");
 
 #[tokio::main]
 async fn main() {
@@ -109,74 +319,410 @@ async fn main() {
         .and_then(|interval| humantime::parse_duration(&interval).ok())
         .unwrap_or(Duration::from_millis(10));
 
-    #[rustfmt::skip]
-    let accounts = HashMap::from_iter([
-        (1, Mutex::new(Account::with_db(db.join("account-1.espora"), fsync_interval, 100_000).await.unwrap())),
-        (2, Mutex::new(Account::with_db(db.join("account-2.espora"), fsync_interval, 80_000).await.unwrap())),
-        (3, Mutex::new(Account::with_db(db.join("account-3.espora"), fsync_interval, 1_000_000).await.unwrap())),
-        (4, Mutex::new(Account::with_db(db.join("account-4.espora"), fsync_interval, 10_000_000).await.unwrap())),
-        (5, Mutex::new(Account::with_db(db.join("account-5.espora"), fsync_interval, 500_000).await.unwrap())),
-    ]);
-
-    let app = Router::new()
-        .route("/clientes/:id/transacoes", post(create_transaction))
-        .route("/clientes/:id/extrato", get(view_account))
-        .with_state(Arc::new(accounts));
+    let mut accounts = HashMap::new();
+    for (id, limit) in rinha::load_accounts().unwrap() {
+        let path = db.join(format!("account-{id}.espora"));
+        let account = Account::with_db(path, fsync_interval, limit).await.unwrap();
+        accounts.insert(id, account);
+    }
 
     println!("App ({}) ready {unix_socket}", env!("CARGO_PKG_VERSION"));
 
-    axum_unix_socket::serve(unix_socket, app).await.unwrap();
+    serve(unix_socket, Arc::new(accounts), shutdown_signal())
+        .await
+        .unwrap();
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
 
+/// Serves until `shutdown` resolves, then takes the write lock of every account and syncs its db,
+/// so the transactions written since the last periodic sync are durable before exiting.
+async fn serve(
+    unix_socket: String,
+    accounts: AppState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    axum_unix_socket::serve_with_shutdown(unix_socket, router(accounts.clone()), shutdown).await?;
+
+    for account in accounts.values() {
+        let mut writer = account.writer.lock().await;
+        let lock = writer.db.lock_writes().await?;
+        writer.db.sync().await?;
+        drop(lock);
+    }
+
+    Ok(())
+}
+
+fn router(accounts: AppState) -> Router {
+    Router::new()
+        .route(
+            "/clientes/:id/transacoes",
+            post(create_transaction).layer(DefaultBodyLimit::max(MAX_TRANSACTION_SIZE)),
+        )
+        .route("/clientes/:id/extrato", get(view_account))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn(track_metrics))
+        .with_state(accounts)
+}
+
+/// Counts the requests to each route by status, along with how long they took.
+async fn track_metrics(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    METRICS.increment(
+        "rinha_requests_total",
+        &[("route", &route), ("status", response.status().as_str())],
+    );
+    METRICS.observe(
+        "rinha_request_duration_seconds",
+        &[("route", &route)],
+        elapsed.as_secs_f64(),
+    );
+
+    response
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)],
+        METRICS.render(),
+    )
+}
+
+/// Whether the db file of every account can still be opened.
+async fn health(State(accounts): State<AppState>) -> Result<StatusCode, ApiError> {
+    for account in accounts.values() {
+        AccountDb::open_read_only(&account.path)
+            .await
+            .map_err(|_| ACCOUNT_UNAVAILABLE)?;
+    }
+    Ok(StatusCode::OK)
+}
+
+/// Whether the db of every account is initialized and its write lock can be taken, which other
+/// instances sharing the files hold while writing.
+async fn ready(State(accounts): State<AppState>) -> Result<StatusCode, ApiError> {
+    for account in accounts.values() {
+        let mut writer = account.writer.lock().await;
+        writer
+            .db
+            .lock_writes_timeout(READY_TIMEOUT)
+            .await
+            .map_err(|_| ACCOUNT_UNAVAILABLE)?;
+    }
+    Ok(StatusCode::OK)
+}
+
+/// Requests with an `Idempotency-Key` header are only applied once, repeats get the same response.
 async fn create_transaction(
-    Path(account_id): Path<u8>,
+    Path(account_id): Path<AccountId>,
     State(accounts): State<AppState>,
-    Json(transaction): Json<Transaction>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+    transaction: Result<Json<Transaction>, JsonRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Json(transaction) = transaction?;
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned);
+
     match accounts.get(&account_id) {
         Some(account) => {
-            let mut account = account.lock().await;
-            match account.transact(transaction).await {
+            let result = match idempotency_key {
+                Some(key) => account.transact_once(key, transaction).await,
+                None => account.transact(transaction).await,
+            };
+            METRICS.increment(
+                "rinha_transactions_total",
+                &[
+                    ("account", &account_id.to_string()),
+                    ("result", if result.is_ok() { "ok" } else { "rejected" }),
+                ],
+            );
+            match result {
                 Ok(balance) => Ok(Json(json!({
                     "limite": account.limit,
                     "saldo": balance,
                 }))),
-                Err(_) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+                Err(err) => Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, err)),
             }
         }
-        None => Err(StatusCode::NOT_FOUND),
+        None => Err(ACCOUNT_NOT_FOUND),
     }
 }
 
+/// Transactions can be filtered with `?from=` and `?to=`. Paging with `?limit=` or `?before=`
+/// instead includes the `cursor` of the next page in the response.
 async fn view_account(
-    Path(account_id): Path<u8>,
+    Path(account_id): Path<AccountId>,
+    Query(range): Query<DateRange>,
+    Query(pagination): Query<Pagination>,
     State(accounts): State<AppState>,
-) -> impl IntoResponse {
-    match accounts.get(&account_id) {
-        Some(account) => {
-            let mut account = account.lock().await;
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(account) = accounts.get(&account_id) else {
+        return Err(ACCOUNT_NOT_FOUND);
+    };
+    METRICS.increment(
+        "rinha_account_views_total",
+        &[("account", &account_id.to_string())],
+    );
 
-            let transactions = account.last_transactions(10).await.unwrap_or_default();
+    if range.is_requested() {
+        let (balance, transactions) = account.transactions_between(&range).await?;
 
-            let balance = transactions
-                .first()
-                .map(|(balance, _)| *balance)
-                .unwrap_or_default();
+        Ok(Json(json!({
+            "saldo": {
+                "total": balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": transactions,
+        })))
+    } else if pagination.is_requested() {
+        let (balance, transactions, cursor) = account
+            .transactions_page(pagination.limit(), pagination.before)
+            .await?;
 
-            let transactions = transactions
-                .into_iter()
-                .map(|(_, txn)| txn)
-                .collect::<Vec<_>>();
+        let transactions = transactions
+            .into_iter()
+            .map(|(_, txn)| txn)
+            .collect::<Vec<_>>();
 
-            Ok(Json(json!({
-                "saldo": {
-                    "total": balance,
-                    "data_extrato": DateTime::now(),
-                    "limite": account.limit,
-                },
-                "ultimas_transacoes": transactions,
-            })))
+        Ok(Json(json!({
+            "saldo": {
+                "total": balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": transactions,
+            "cursor": cursor,
+        })))
+    } else {
+        let transactions = account.last_transactions(10).await.unwrap_or_default();
+
+        let balance = transactions
+            .first()
+            .map(|(balance, _)| *balance)
+            .unwrap_or_default();
+
+        let transactions = transactions
+            .into_iter()
+            .map(|(_, txn)| txn)
+            .collect::<Vec<_>>();
+
+        Ok(Json(json!({
+            "saldo": {
+                "total": balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": transactions,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+        sync::oneshot,
+        task::JoinSet,
+        time,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reads_while_writing() {
+        let tmp = tempdir().unwrap();
+        let account = Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 0)
+            .await
+            .unwrap();
+        let account = Arc::new(account);
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..20 {
+            let credit = serde_json::from_str::<Transaction>(
+                r#"{"valor": 1, "tipo": "c", "descricao": "Rinha"}"#,
+            )
+            .unwrap();
+            let writer = account.clone();
+            tasks.spawn(async move {
+                writer.transact(credit).await.unwrap();
+            });
+
+            let reader = account.clone();
+            tasks.spawn(async move {
+                reader.last_transactions(10).await.unwrap();
+            });
+        }
+        while let Some(task) = tasks.join_next().await {
+            task.unwrap();
+        }
+
+        // Reads don't wait for a write holding the account
+        let _writing = account.writer.lock().await;
+        let last = time::timeout(Duration::from_secs(1), account.last_transactions(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(20, last[0].0);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_retries_db_failures() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let account = Account::with_db(&path, Duration::ZERO, 0).await.unwrap();
+        let credit = serde_json::from_str::<Transaction>(
+            r#"{"valor": 100, "tipo": "c", "descricao": "Rinha"}"#,
+        )
+        .unwrap();
+        let debit = serde_json::from_str::<Transaction>(
+            r#"{"valor": 500, "tipo": "d", "descricao": "Rinha"}"#,
+        )
+        .unwrap();
+
+        // Writes fail while the db is read-only
+        let db = AccountDb::open_read_only(&path).await.unwrap();
+        let db = std::mem::replace(&mut account.writer.lock().await.db, db);
+        assert!(account
+            .transact_once(String::from("credit"), credit.clone())
+            .await
+            .is_err());
+        account.writer.lock().await.db = db;
+
+        assert_eq!(
+            Ok(100),
+            account
+                .transact_once(String::from("credit"), credit.clone())
+                .await
+        );
+        assert_eq!(
+            Ok(100),
+            account
+                .transact_once(String::from("credit"), credit.clone())
+                .await
+        );
+
+        // Rejections are kept, even once the transaction would go through
+        let rejected = account
+            .transact_once(String::from("debit"), debit.clone())
+            .await;
+        assert!(rejected.is_err());
+        for _ in 0..4 {
+            account.transact(credit.clone()).await.unwrap();
+        }
+        assert_eq!(
+            rejected,
+            account.transact_once(String::from("debit"), debit).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statement_matches_balance() {
+        let tmp = tempdir().unwrap();
+        let account = Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 0)
+            .await
+            .unwrap();
+        let account = Arc::new(account);
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..50 {
+            let credit = serde_json::from_str::<Transaction>(
+                r#"{"valor": 1, "tipo": "c", "descricao": "Rinha"}"#,
+            )
+            .unwrap();
+            let writer = account.clone();
+            tasks.spawn(async move {
+                writer.transact(credit).await.unwrap();
+            });
+
+            let reader = account.clone();
+            tasks.spawn(async move {
+                let (balance, rows, _) = reader.transactions_page(100, None).await.unwrap();
+                let total = rows
+                    .iter()
+                    .rev()
+                    .try_fold(0, |total, (_, txn)| txn.apply(total, 0))
+                    .unwrap();
+                assert_eq!(balance, total);
+            });
         }
-        None => Err(StatusCode::NOT_FOUND),
+        while let Some(task) = tasks.join_next().await {
+            task.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_on_shutdown() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let socket = tmp.path().join("test.socket");
+
+        // Only the shutdown gets to sync
+        let account = Account::with_db(&path, Duration::from_secs(3600), 0)
+            .await
+            .unwrap();
+        let accounts = Arc::new(HashMap::from([(1, account)]));
+
+        let (shutdown, signal) = oneshot::channel();
+        let client = tokio::spawn({
+            let socket = socket.clone();
+            async move {
+                let mut stream = loop {
+                    match UnixStream::connect(&socket).await {
+                        Ok(stream) => break stream,
+                        Err(_) => time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+
+                let body = r#"{"valor": 5, "tipo": "c", "descricao": "Rinha"}"#;
+                let request = format!(
+                    "POST /clientes/1/transacoes HTTP/1.1\r\nHost: localhost\r\n\
+                     Content-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                assert!(response.starts_with("HTTP/1.1 200"));
+
+                shutdown.send(()).unwrap();
+            }
+        });
+
+        let signal = async {
+            signal.await.ok();
+        };
+        serve(socket.to_str().unwrap().to_owned(), accounts, signal)
+            .await
+            .unwrap();
+        client.await.unwrap();
+
+        let account = Account::with_db(&path, Duration::ZERO, 0).await.unwrap();
+        let last = account.last_transactions(1).await.unwrap();
+        assert_eq!(5, last[0].0);
     }
 }
@@ -1,29 +1,55 @@
 use std::{
-    collections::HashMap, env, error::Error, path::Path as FilePath, sync::Arc, time::Duration,
+    collections::HashMap,
+    env,
+    error::Error,
+    future::Future,
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{
+        rejection::JsonRejection, DefaultBodyLimit, MatchedPath, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use espora_db::{Db, Error as DbError};
 use ring_buffer::RingBuffer;
-use rinha::{DateTime, Transaction, TransactionType};
+use rinha::{
+    AccountId, DateRange, DateTime, IdempotencyKeys, Pagination, Transaction,
+    IDEMPOTENCY_KEY_HEADER, METRICS, METRICS_CONTENT_TYPE,
+};
 use serde_json::json;
-use tokio::sync::RwLock;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::RwLock,
+    task, time,
+};
 
 mod ring_buffer;
 
 type Balance = i64;
 
+/// Largest transaction body accepted, way over what a valid transaction takes.
+const MAX_TRANSACTION_SIZE: usize = 4 * 1024;
+
+/// How long `/ready` waits for the write lock of each account.
+const READY_TIMEOUT: Duration = Duration::from_millis(100);
+
+type AccountDb = Db<(Balance, Transaction), 128>;
+
 struct Account {
     balance: Balance,
     limit: i64,
     transactions: RingBuffer<Transaction, 10>,
-    db: Db<(Balance, Transaction), 128>,
+    idempotency_keys: IdempotencyKeys<Result<Balance, &'static str>>,
+    path: PathBuf,
+    db: AccountDb,
 }
 
 impl Account {
@@ -32,9 +58,10 @@ impl Account {
         fsync_interval: Duration,
         limit: i64,
     ) -> Result<Self, Box<dyn Error>> {
-        let mut db = Db::<(i64, Transaction), 128>::builder()
+        let path = path.as_ref().to_path_buf();
+        let mut db = AccountDb::builder()
             .sync_write_interval(fsync_interval)
-            .build(path)?;
+            .build(&path)?;
 
         let transactions = db
             .rows_reverse()
@@ -53,31 +80,149 @@ impl Account {
                 .into_iter()
                 .map(|(_, transaction)| transaction)
                 .collect(),
+            idempotency_keys: IdempotencyKeys::default(),
+            path,
             db,
         })
     }
 
     pub fn transact(&mut self, transaction: Transaction) -> Result<(), &'static str> {
-        let balance = match transaction.kind {
-            TransactionType::Credit => self.balance + transaction.value,
-            TransactionType::Debit => {
-                if self.balance + self.limit >= transaction.value {
-                    self.balance - transaction.value
-                } else {
-                    return Err("Não tem limite o suficiente");
-                }
-            }
+        self.write(transaction).and_then(|result| result)
+    }
+
+    /// Same as [`Account::transact`], but a key seen recently gets the result of its first
+    /// transaction back instead of applying another one.
+    pub fn transact_once(
+        &mut self,
+        key: String,
+        transaction: Transaction,
+    ) -> Result<Balance, &'static str> {
+        if let Some(result) = self.idempotency_keys.get(&key) {
+            return *result;
+        }
+
+        // Failures of the db aren't kept, so retrying the key still gets the transaction applied
+        let result = self.write(transaction)?.map(|()| self.balance);
+        self.idempotency_keys.insert(key, result);
+        result
+    }
+
+    /// Applies the transaction to the balance and stores it. Fails with the outer error when the
+    /// db does, and with the inner one when the transaction is rejected.
+    fn write(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Result<(), &'static str>, &'static str> {
+        let balance = match transaction.apply(self.balance, self.limit) {
+            Ok(balance) => balance,
+            Err(err) => return Ok(Err(err)),
         };
         self.db
             .insert((balance, transaction.clone()))
             .map_err(|_| "Erro ao persistir")?;
         self.balance = balance;
         self.transactions.push_front(transaction);
+        Ok(Ok(()))
+    }
+
+    /// Up to `limit` transactions older than the `before` cursor, newest first, along with the
+    /// cursor of the next page when there are older ones left.
+    pub fn transactions_page(
+        &mut self,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<(Vec<Transaction>, Option<u64>), DbError> {
+        let mut rows = self
+            .db
+            .rows_reverse_with_offset()
+            .skip_while(|row| {
+                matches!(row, Ok((offset, _)) if before.is_some_and(|before| *offset >= before))
+            })
+            .take(limit + 1)
+            .collect::<Result<Vec<_>, DbError>>()?;
+
+        let cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(|(offset, _)| *offset)
+        } else {
+            None
+        };
+
+        let transactions = rows
+            .into_iter()
+            .map(|(_, (_, transaction))| transaction)
+            .collect();
+
+        Ok((transactions, cursor))
+    }
+
+    /// Transactions made within the range, newest first. The scan stops at the first transaction
+    /// older than the range, as they are stored in the order they were made.
+    pub fn transactions_between(&mut self, range: &DateRange) -> Result<Vec<Transaction>, DbError> {
+        self.db
+            .rows_reverse()
+            .map(|row| row.map(|(_, transaction)| transaction))
+            .skip_while(|row| matches!(row, Ok(txn) if range.is_after(&txn.created_at)))
+            .take_while(|row| !matches!(row, Ok(txn) if range.is_before(&txn.created_at)))
+            .collect()
+    }
+
+    /// Replays every transaction from the start, failing at the first row whose stored balance
+    /// doesn't match the recomputed one, or when the last one doesn't match the loaded balance.
+    pub fn verify_balance(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut balance = 0;
+        for (index, row) in self.db.rows().enumerate() {
+            let (stored, transaction) = row?;
+            balance = transaction
+                .apply(balance, self.limit)
+                .map_err(|err| format!("row {index}: {err}"))?;
+            if stored != balance {
+                return Err(
+                    format!("row {index}: stored balance {stored}, recomputed {balance}").into(),
+                );
+            }
+        }
+
+        if self.balance != balance {
+            return Err(format!("loaded balance {}, recomputed {balance}", self.balance).into());
+        }
+
         Ok(())
     }
 }
 
-type AppState = Arc<HashMap<u8, RwLock<Account>>>;
+type AppState = Arc<HashMap<AccountId, RwLock<Account>>>;
+
+/// An error response, with the message in a JSON body like `{"error": "..."}`.
+#[derive(Debug)]
+struct ApiError(StatusCode, &'static str);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let ApiError(status, message) = self;
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(_: DbError) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler do db")
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self(rejection.status(), "Transação muito grande"),
+            status => Self(status, "Transação inválida"),
+        }
+    }
+}
+
+const ACCOUNT_NOT_FOUND: ApiError = ApiError(StatusCode::NOT_FOUND, "Cliente não encontrado");
+
+const ACCOUNT_UNAVAILABLE: ApiError =
+    ApiError(StatusCode::SERVICE_UNAVAILABLE, "Conta indisponível");
 
 #[tokio::main]
 async fn main() {
@@ -90,61 +235,656 @@ async fn main() {
         .and_then(|interval| humantime::parse_duration(&interval).ok())
         .unwrap_or(Duration::from_millis(10));
 
-    #[rustfmt::skip]
-    let accounts = HashMap::from_iter([
-        (1, RwLock::new(Account::with_db("account-1.espora", fsync_interval, 100_000).unwrap())),
-        (2, RwLock::new(Account::with_db("account-2.espora", fsync_interval, 80_000).unwrap())),
-        (3, RwLock::new(Account::with_db("account-3.espora", fsync_interval, 1_000_000).unwrap())),
-        (4, RwLock::new(Account::with_db("account-4.espora", fsync_interval, 10_000_000).unwrap())),
-        (5, RwLock::new(Account::with_db("account-5.espora", fsync_interval, 500_000).unwrap())),
-    ]);
-
-    let app = Router::new()
-        .route("/clientes/:id/transacoes", post(create_transaction))
-        .route("/clientes/:id/extrato", get(view_account))
-        .with_state(Arc::new(accounts));
+    let verify_balances = env::var("ESPORA_VERIFY_BALANCES")
+        .ok()
+        .and_then(|verify| verify.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    let accounts = rinha::load_accounts()
+        .unwrap()
+        .into_iter()
+        .map(|(id, limit)| {
+            let path = format!("account-{id}.espora");
+            let mut account = Account::with_db(path, fsync_interval, limit).unwrap();
+            if verify_balances {
+                if let Err(err) = account.verify_balance() {
+                    panic!("account {id} is inconsistent: {err}");
+                }
+            }
+            (id, RwLock::new(account))
+        })
+        .collect::<HashMap<_, _>>();
 
     println!("DB ({}) ready {unix_socket}", env!("CARGO_PKG_VERSION"));
 
-    axum_unix_socket::serve(unix_socket, app).await.unwrap();
+    serve(unix_socket, Arc::new(accounts), shutdown_signal())
+        .await
+        .unwrap();
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Serves until `shutdown` resolves, then takes the write lock of every account and syncs its db,
+/// so the transactions written since the last periodic sync are durable before exiting.
+async fn serve(
+    unix_socket: String,
+    accounts: AppState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    axum_unix_socket::serve_with_shutdown(unix_socket, router(accounts.clone()), shutdown).await?;
+
+    for account in accounts.values() {
+        let mut account = account.write().await;
+        account.db.sync()?;
+    }
+
+    Ok(())
+}
+
+fn router(accounts: AppState) -> Router {
+    Router::new()
+        .route(
+            "/clientes/:id/transacoes",
+            post(create_transaction).layer(DefaultBodyLimit::max(MAX_TRANSACTION_SIZE)),
+        )
+        .route("/clientes/:id/extrato", get(view_account))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn(track_metrics))
+        .with_state(accounts)
+}
+
+/// Counts the requests to each route by status, along with how long they took.
+async fn track_metrics(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    METRICS.increment(
+        "rinha_requests_total",
+        &[("route", &route), ("status", response.status().as_str())],
+    );
+    METRICS.observe(
+        "rinha_request_duration_seconds",
+        &[("route", &route)],
+        elapsed.as_secs_f64(),
+    );
+
+    response
 }
 
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)],
+        METRICS.render(),
+    )
+}
+
+/// Whether the db file of every account can still be opened, which blocks, so it's done off the
+/// runtime threads.
+async fn health(State(accounts): State<AppState>) -> Result<StatusCode, ApiError> {
+    for account in accounts.values() {
+        let path = account.read().await.path.clone();
+        task::spawn_blocking(move || AccountDb::open_read_only(path))
+            .await
+            .map_err(|_| ACCOUNT_UNAVAILABLE)?
+            .map_err(|_| ACCOUNT_UNAVAILABLE)?;
+    }
+    Ok(StatusCode::OK)
+}
+
+/// Whether the db of every account is initialized and its write lock can be taken. The account
+/// is only held for each attempt, so requests go on while waiting for another process.
+async fn ready(State(accounts): State<AppState>) -> Result<StatusCode, ApiError> {
+    for account in accounts.values() {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            let locked = account
+                .write()
+                .await
+                .db
+                .try_lock_writes()
+                .map_err(|_| ACCOUNT_UNAVAILABLE)?;
+            if locked.is_some() {
+                break;
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= READY_TIMEOUT {
+                return Err(ACCOUNT_UNAVAILABLE);
+            }
+            time::sleep(backoff.min(READY_TIMEOUT - elapsed)).await;
+            backoff *= 2;
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+/// Requests with an `Idempotency-Key` header are only applied once, repeats get the same response.
 async fn create_transaction(
-    Path(account_id): Path<u8>,
+    Path(account_id): Path<AccountId>,
     State(accounts): State<AppState>,
-    Json(transaction): Json<Transaction>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+    transaction: Result<Json<Transaction>, JsonRejection>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Json(transaction) = transaction?;
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned);
+
     match accounts.get(&account_id) {
         Some(account) => {
             let mut account = account.write().await;
-            match account.transact(transaction) {
-                Ok(()) => Ok(Json(json!({
+            let result = match idempotency_key {
+                Some(key) => account.transact_once(key, transaction),
+                None => account.transact(transaction).map(|()| account.balance),
+            };
+            METRICS.increment(
+                "rinha_transactions_total",
+                &[
+                    ("account", &account_id.to_string()),
+                    ("result", if result.is_ok() { "ok" } else { "rejected" }),
+                ],
+            );
+            match result {
+                Ok(balance) => Ok(Json(json!({
                     "limite": account.limit,
-                    "saldo": account.balance,
+                    "saldo": balance,
                 }))),
-                Err(_) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+                Err(err) => Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, err)),
             }
         }
-        None => Err(StatusCode::NOT_FOUND),
+        None => Err(ACCOUNT_NOT_FOUND),
     }
 }
 
+/// The latest transactions come from memory, only reading the db when filtering with `?from=` or
+/// `?to=`, or when paging with `?limit=` or `?before=`, in which case the response includes the
+/// `cursor` of the next page.
 async fn view_account(
-    Path(account_id): Path<u8>,
+    Path(account_id): Path<AccountId>,
+    Query(range): Query<DateRange>,
+    Query(pagination): Query<Pagination>,
     State(accounts): State<AppState>,
-) -> impl IntoResponse {
-    match accounts.get(&account_id) {
-        Some(account) => {
-            let account = account.read().await;
-            Ok(Json(json!({
-                "saldo": {
-                    "total": account.balance,
-                    "data_extrato": DateTime::now(),
-                    "limite": account.limit,
-                },
-                "ultimas_transacoes": account.transactions,
-            })))
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(account) = accounts.get(&account_id) else {
+        return Err(ACCOUNT_NOT_FOUND);
+    };
+    METRICS.increment(
+        "rinha_account_views_total",
+        &[("account", &account_id.to_string())],
+    );
+
+    if range.is_requested() {
+        let mut account = account.write().await;
+        let transactions = account.transactions_between(&range)?;
+        Ok(Json(json!({
+            "saldo": {
+                "total": account.balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": transactions,
+        })))
+    } else if pagination.is_requested() {
+        let mut account = account.write().await;
+        let (transactions, cursor) =
+            account.transactions_page(pagination.limit(), pagination.before)?;
+        Ok(Json(json!({
+            "saldo": {
+                "total": account.balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": transactions,
+            "cursor": cursor,
+        })))
+    } else {
+        let account = account.read().await;
+        Ok(Json(json!({
+            "saldo": {
+                "total": account.balance,
+                "data_extrato": DateTime::now(),
+                "limite": account.limit,
+            },
+            "ultimas_transacoes": account.transactions,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{self, Body},
+        http::{header, Request},
+    };
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+        sync::oneshot,
+        time,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_account_id_over_u8() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1000.espora"), Duration::ZERO, 500).unwrap();
+        let app = router(Arc::new(HashMap::from([(1000, RwLock::new(account))])));
+
+        let request = Request::post("/clientes/1000/transacoes")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"valor": 300, "tipo": "d", "descricao": "Rinha"}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json!({"limite": 500, "saldo": -300}), body);
+    }
+
+    async fn respond(app: &Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> serde_json::Value {
+        let (status, body) = respond(app, request).await;
+        assert_eq!(StatusCode::OK, status);
+        body
+    }
+
+    #[tokio::test]
+    async fn test_paginate_account_view() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 0).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        for i in 0..25 {
+            let request = Request::post("/clientes/1/transacoes")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    r#"{{"valor": 1, "tipo": "c", "descricao": "{i}"}}"#
+                )))
+                .unwrap();
+            send(&app, request).await;
+        }
+
+        let mut descriptions = Vec::new();
+        let mut pages = Vec::new();
+        let mut uri = String::from("/clientes/1/extrato?limit=10");
+        loop {
+            let page = send(&app, Request::get(&uri).body(Body::empty()).unwrap()).await;
+            assert_eq!(25, page["saldo"]["total"]);
+
+            let transactions = page["ultimas_transacoes"].as_array().unwrap();
+            pages.push(transactions.len());
+            descriptions.extend(transactions.iter().map(|txn| txn["descricao"].clone()));
+
+            match page["cursor"].as_u64() {
+                Some(cursor) => uri = format!("/clientes/1/extrato?limit=10&before={cursor}"),
+                None => break,
+            }
+        }
+
+        assert_eq!(vec![10, 10, 5], pages);
+        assert_eq!(
+            (0..25)
+                .rev()
+                .map(|i| json!(i.to_string()))
+                .collect::<Vec<_>>(),
+            descriptions
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filter_account_view_by_date() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 0).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        for day in 1..=5 {
+            let request = Request::post("/clientes/1/transacoes")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    r#"{{"valor": 1, "tipo": "c", "descricao": "{day}", "realizada_em": "2024-02-0{day}T12:00:00Z"}}"#
+                )))
+                .unwrap();
+            send(&app, request).await;
+        }
+
+        let descriptions = |view: serde_json::Value| {
+            view["ultimas_transacoes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|txn| txn["descricao"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let view = |query: &str| {
+            let request = Request::get(format!("/clientes/1/extrato?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            send(&app, request)
+        };
+
+        let between = view("from=2024-02-02T12:00:00Z&to=2024-02-04T12:00:00Z").await;
+        assert_eq!(vec!["3", "2"], descriptions(between));
+
+        let from = view("from=2024-02-04T00:00:00Z").await;
+        assert_eq!(vec!["5", "4"], descriptions(from));
+
+        let to = view("to=2024-02-02T00:00:00Z").await;
+        assert_eq!(vec!["1"], descriptions(to));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 1000).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        let debit = |key: &str| {
+            Request::post("/clientes/1/transacoes")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(IDEMPOTENCY_KEY_HEADER, key)
+                .body(Body::from(
+                    r#"{"valor": 100, "tipo": "d", "descricao": "Rinha"}"#,
+                ))
+                .unwrap()
+        };
+
+        let first = send(&app, debit("rinha")).await;
+        assert_eq!(json!({"limite": 1000, "saldo": -100}), first);
+        let retry = send(&app, debit("rinha")).await;
+        assert_eq!(first, retry);
+
+        let extrato = Request::get("/clientes/1/extrato")
+            .body(Body::empty())
+            .unwrap();
+        let view = send(&app, extrato).await;
+        assert_eq!(-100, view["saldo"]["total"]);
+        assert_eq!(1, view["ultimas_transacoes"].as_array().unwrap().len());
+
+        let other = send(&app, debit("backend")).await;
+        assert_eq!(json!({"limite": 1000, "saldo": -200}), other);
+    }
+
+    #[tokio::test]
+    async fn test_json_errors() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 100).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        let transaction = |id: u32, body: &'static str| {
+            Request::post(format!("/clientes/{id}/transacoes"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let over_limit = transaction(1, r#"{"valor": 101, "tipo": "d", "descricao": "Rinha"}"#);
+        assert_eq!(
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({"error": "Não tem limite o suficiente"})
+            ),
+            respond(&app, over_limit).await
+        );
+
+        let invalid = transaction(1, r#"{"valor": 0, "tipo": "d", "descricao": "Rinha"}"#);
+        assert_eq!(
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({"error": "Transação inválida"})
+            ),
+            respond(&app, invalid).await
+        );
+
+        let missing = transaction(2, r#"{"valor": 1, "tipo": "d", "descricao": "Rinha"}"#);
+        assert_eq!(
+            (
+                StatusCode::NOT_FOUND,
+                json!({"error": "Cliente não encontrado"})
+            ),
+            respond(&app, missing).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_size_limit() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-1.espora"), Duration::ZERO, 100).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        let padding = " ".repeat(MAX_TRANSACTION_SIZE);
+        let request = Request::post("/clientes/1/transacoes")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                r#"{{"valor": 1, "tipo": "c", "descricao": "Rinha"}}{padding}"#
+            )))
+            .unwrap();
+        assert_eq!(
+            (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({"error": "Transação muito grande"})
+            ),
+            respond(&app, request).await
+        );
+    }
+
+    #[test]
+    fn test_idempotency_key_retries_db_failures() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let mut account = Account::with_db(&path, Duration::ZERO, 0).unwrap();
+        let transaction = |value: i64, kind: &str| {
+            serde_json::from_str::<Transaction>(&format!(
+                r#"{{"valor": {value}, "tipo": "{kind}", "descricao": "Rinha"}}"#
+            ))
+            .unwrap()
+        };
+
+        // Writes fail while the db is read-only
+        let db = std::mem::replace(&mut account.db, AccountDb::open_read_only(&path).unwrap());
+        assert!(account
+            .transact_once(String::from("credit"), transaction(100, "c"))
+            .is_err());
+        account.db = db;
+
+        for _ in 0..2 {
+            assert_eq!(
+                Ok(100),
+                account.transact_once(String::from("credit"), transaction(100, "c"))
+            );
+        }
+
+        // Rejections are kept, even once the transaction would go through
+        let rejected = account.transact_once(String::from("debit"), transaction(500, "d"));
+        assert!(rejected.is_err());
+        account.transact(transaction(400, "c")).unwrap();
+        assert_eq!(
+            rejected,
+            account.transact_once(String::from("debit"), transaction(500, "d"))
+        );
+    }
+
+    #[test]
+    fn test_verify_balance() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let transaction = |kind: &str| {
+            serde_json::from_str::<Transaction>(&format!(
+                r#"{{"valor": 100, "tipo": "{kind}", "descricao": "Rinha"}}"#
+            ))
+            .unwrap()
+        };
+
+        let mut account = Account::with_db(&path, Duration::ZERO, 1000).unwrap();
+        account.transact(transaction("c")).unwrap();
+        account.transact(transaction("d")).unwrap();
+        account.transact(transaction("d")).unwrap();
+        assert!(account.verify_balance().is_ok());
+
+        // A last row claiming a balance the transactions don't add up to
+        account.db.insert((500, transaction("c"))).unwrap();
+        drop(account);
+
+        let mut account = Account::with_db(&path, Duration::ZERO, 1000).unwrap();
+        assert_eq!(500, account.balance);
+        let err = account.verify_balance().unwrap_err();
+        assert_eq!("row 3: stored balance 500, recomputed 0", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_health_and_ready() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let account = Account::with_db(&path, Duration::ZERO, 0).unwrap();
+        let app = router(Arc::new(HashMap::from([(1, RwLock::new(account))])));
+
+        let check = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(StatusCode::OK, check("/health").await);
+        assert_eq!(StatusCode::OK, check("/ready").await);
+
+        let mut other = AccountDb::from_path(&path).unwrap();
+        let lock = other.lock_writes().unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, check("/ready").await);
+        drop(lock);
+        assert_eq!(StatusCode::OK, check("/ready").await);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, check("/health").await);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let tmp = tempdir().unwrap();
+        let account =
+            Account::with_db(tmp.path().join("account-72.espora"), Duration::ZERO, 0).unwrap();
+        let app = router(Arc::new(HashMap::from([(72, RwLock::new(account))])));
+
+        let transactions = || async {
+            let request = Request::get("/metrics").body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(
+                METRICS_CONTENT_TYPE,
+                response.headers()[header::CONTENT_TYPE]
+            );
+
+            let body = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix(r#"rinha_transactions_total{account="72",result="ok"} "#)
+                })
+                .map(|count| count.parse::<u64>().unwrap())
+                .unwrap_or_default()
+        };
+
+        assert_eq!(0, transactions().await);
+        for _ in 0..2 {
+            let request = Request::post("/clientes/72/transacoes")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    r#"{"valor": 1, "tipo": "c", "descricao": "Rinha"}"#,
+                ))
+                .unwrap();
+            send(&app, request).await;
         }
-        None => Err(StatusCode::NOT_FOUND),
+        assert_eq!(2, transactions().await);
+    }
+
+    #[tokio::test]
+    async fn test_sync_on_shutdown() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("account-1.espora");
+        let socket = tmp.path().join("test.socket");
+
+        // Only the shutdown gets to sync
+        let account = Account::with_db(&path, Duration::from_secs(3600), 0).unwrap();
+        let accounts = Arc::new(HashMap::from([(1, RwLock::new(account))]));
+
+        let (shutdown, signal) = oneshot::channel();
+        let client = tokio::spawn({
+            let socket = socket.clone();
+            async move {
+                let mut stream = loop {
+                    match UnixStream::connect(&socket).await {
+                        Ok(stream) => break stream,
+                        Err(_) => time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+
+                let body = r#"{"valor": 5, "tipo": "c", "descricao": "Rinha"}"#;
+                let request = format!(
+                    "POST /clientes/1/transacoes HTTP/1.1\r\nHost: localhost\r\n\
+                     Content-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                assert!(response.starts_with("HTTP/1.1 200"));
+
+                shutdown.send(()).unwrap();
+            }
+        });
+
+        let signal = async {
+            signal.await.ok();
+        };
+        serve(socket.to_str().unwrap().to_owned(), accounts, signal)
+            .await
+            .unwrap();
+        client.await.unwrap();
+
+        let account = Account::with_db(&path, Duration::ZERO, 0).unwrap();
+        assert_eq!(5, account.balance);
     }
 }
@@ -1,11 +1,11 @@
 use std::collections::{vec_deque, VecDeque};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RingBuffer<T, const SIZE: usize>(VecDeque<T>);
 
-impl<T> Default for RingBuffer<T, 10> {
+impl<const SIZE: usize, T> Default for RingBuffer<T, SIZE> {
     fn default() -> Self {
         Self::new()
     }
@@ -17,7 +17,7 @@ impl<const SIZE: usize, T> RingBuffer<T, SIZE> {
     }
 
     pub fn push_front(&mut self, item: T) {
-        if self.0.len() == self.0.capacity() {
+        if self.0.len() == SIZE {
             self.0.pop_back();
             self.0.push_front(item);
         } else {
@@ -26,13 +26,42 @@ impl<const SIZE: usize, T> RingBuffer<T, SIZE> {
     }
 
     pub fn push_back(&mut self, item: T) {
-        if self.0.len() == self.0.capacity() {
+        if self.0.len() == SIZE {
             self.0.pop_front();
             self.0.push_back(item);
         } else {
             self.0.push_back(item);
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// How many items the buffer holds before dropping the oldest ones.
+    pub fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.0.get(index)
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.0.front()
+    }
+
+    pub fn back(&self) -> Option<&T> {
+        self.0.back()
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, T> {
+        self.0.iter()
+    }
 }
 
 impl<const SIZE: usize, T> IntoIterator for RingBuffer<T, SIZE> {
@@ -44,6 +73,22 @@ impl<const SIZE: usize, T> IntoIterator for RingBuffer<T, SIZE> {
     }
 }
 
+impl<'a, const SIZE: usize, T> IntoIterator for &'a RingBuffer<T, SIZE> {
+    type Item = &'a T;
+    type IntoIter = vec_deque::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Sequences longer than `SIZE` are loaded as if collected, keeping only their last `SIZE` items.
+impl<'de, const SIZE: usize, T: Deserialize<'de>> Deserialize<'de> for RingBuffer<T, SIZE> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<T>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
 impl<const SIZE: usize, A> FromIterator<A> for RingBuffer<A, SIZE> {
     fn from_iter<T: IntoIterator<Item = A>>(iter: T) -> Self {
         let mut ring_buffer = Self::new();
@@ -53,3 +98,136 @@ impl<const SIZE: usize, A> FromIterator<A> for RingBuffer<A, SIZE> {
         ring_buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_back_keeps_size() {
+        let mut buffer = RingBuffer::<i32, 10>::new();
+        for i in 0..20 {
+            buffer.push_back(i);
+        }
+        assert_eq!(
+            (10..20).collect::<Vec<_>>(),
+            buffer.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_push_front_keeps_size() {
+        let mut buffer = RingBuffer::<i32, 10>::new();
+        for i in 0..20 {
+            buffer.push_front(i);
+        }
+        assert_eq!(
+            (10..20).rev().collect::<Vec<_>>(),
+            buffer.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let mut buffer = RingBuffer::<i32, 3>::default();
+        for i in 0..5 {
+            buffer.push_back(i);
+        }
+        assert_eq!(vec![2, 3, 4], buffer.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_by_reference() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        buffer.push_back(1);
+        buffer.push_back(2);
+
+        assert_eq!(vec![&1, &2], buffer.iter().collect::<Vec<_>>());
+        let mut sum = 0;
+        for item in &buffer {
+            sum += item;
+        }
+        assert_eq!(3, sum);
+
+        buffer.push_back(3);
+        buffer.push_back(4);
+        assert_eq!(vec![2, 3, 4], buffer.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_len() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        assert_eq!(0, buffer.len());
+        buffer.push_back(1);
+        assert_eq!(1, buffer.len());
+        for i in 2..10 {
+            buffer.push_front(i);
+        }
+        assert_eq!(3, buffer.len());
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        assert!(buffer.is_empty());
+        buffer.push_back(1);
+        assert!(!buffer.is_empty());
+        for i in 2..10 {
+            buffer.push_back(i);
+        }
+        assert!(!buffer.is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        assert_eq!(3, buffer.capacity());
+        buffer.push_back(1);
+        assert_eq!(3, buffer.capacity());
+        for i in 2..10 {
+            buffer.push_back(i);
+        }
+        assert_eq!(3, buffer.capacity());
+    }
+
+    #[test]
+    fn test_get() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        assert_eq!(None, buffer.get(0));
+        for i in 0..5 {
+            buffer.push_back(i);
+        }
+        assert_eq!(Some(&2), buffer.get(0));
+        assert_eq!(Some(&4), buffer.get(2));
+        assert_eq!(None, buffer.get(3));
+    }
+
+    #[test]
+    fn test_front_and_back() {
+        let mut buffer = RingBuffer::<i32, 3>::new();
+        assert_eq!(None, buffer.front());
+        assert_eq!(None, buffer.back());
+
+        buffer.push_front(1);
+        assert_eq!(Some(&1), buffer.front());
+        assert_eq!(Some(&1), buffer.back());
+
+        buffer.push_front(2);
+        assert_eq!(Some(&2), buffer.front());
+        assert_eq!(Some(&1), buffer.back());
+    }
+
+    #[test]
+    fn test_deserialize_over_capacity() {
+        let buffer: RingBuffer<i32, 10> =
+            serde_json::from_str("[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]").unwrap();
+        assert_eq!(10, buffer.len());
+        assert_eq!(
+            (1..=10).collect::<Vec<_>>(),
+            buffer.into_iter().collect::<Vec<_>>()
+        );
+
+        let buffer: RingBuffer<i32, 10> = serde_json::from_str("[0, 1, 2]").unwrap();
+        assert_eq!(vec![0, 1, 2], buffer.into_iter().collect::<Vec<_>>());
+    }
+}
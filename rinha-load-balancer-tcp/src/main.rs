@@ -1,10 +1,65 @@
-use std::env;
+use std::{
+    env,
+    fmt::Display,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
+use rinha::{Level, Logger};
 use tokio::{
-    io,
-    net::{TcpListener, UnixStream},
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixStream},
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+    time,
 };
 
+/// How many bytes of a new connection are peeked to find the account of its first request.
+const PEEK_SIZE: usize = 256;
+
+/// How long to wait for the first request of a connection before balancing it by the counter.
+const PEEK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long to wait before accepting again after an error, like running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a shutdown waits for open connections when `DRAIN_TIMEOUT` isn't set.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upstream sockets and whether each accepted a connection on its last probe.
+struct Upstreams {
+    addrs: Vec<&'static str>,
+    up: Vec<AtomicBool>,
+}
+
+impl Upstreams {
+    fn new(addrs: Vec<&'static str>) -> Self {
+        Self {
+            up: addrs.iter().map(|_| AtomicBool::new(true)).collect(),
+            addrs,
+        }
+    }
+
+    /// Tries to connect to every upstream, marking as down the ones that refuse.
+    async fn probe(&self) {
+        for (addr, up) in self.addrs.iter().zip(&self.up) {
+            up.store(UnixStream::connect(addr).await.is_ok(), Ordering::Relaxed);
+        }
+    }
+
+    /// Index of the upstream at `index`, or of the next one up after it. When every upstream is
+    /// down `index` is kept.
+    fn pick(&self, index: usize) -> usize {
+        (0..self.addrs.len())
+            .map(|offset| (index + offset) % self.addrs.len())
+            .find(|index| self.up[*index].load(Ordering::Relaxed))
+            .unwrap_or(index % self.addrs.len())
+    }
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let port = env::var("PORT")
@@ -28,21 +83,408 @@ async fn main() -> io::Result<()> {
         .map(|addr| Box::leak(addr.into_boxed_str()) as &'static str)
         .collect::<Vec<_>>();
 
+    let health_check_interval = env::var("HEALTH_CHECK_INTERVAL")
+        .ok()
+        .and_then(|interval| humantime::parse_duration(&interval).ok())
+        .unwrap_or(Duration::from_secs(1));
+
+    let upstreams: &'static Upstreams = Box::leak(Box::new(Upstreams::new(addrs)));
+    tokio::spawn(async move {
+        let mut ticker = time::interval(health_check_interval);
+        loop {
+            ticker.tick().await;
+            upstreams.probe().await;
+        }
+    });
+
+    let drain_timeout = env::var("DRAIN_TIMEOUT")
+        .ok()
+        .and_then(|timeout| humantime::parse_duration(&timeout).ok())
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+
+    let logger: &'static Logger = Box::leak(Box::new(Logger::from_env()));
     let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
-    let mut counter = 0;
 
     println!("TCP lb ({}) ready 9999", env!("CARGO_PKG_VERSION"));
-    while let Ok((mut downstream, _)) = listener.accept().await {
-        downstream.set_nodelay(true)?;
-        counter += 1;
-        let addr = addrs[counter % addrs.len()];
+    serve(
+        listener,
+        upstreams,
+        logger,
+        drain_timeout,
+        shutdown_signal(),
+    )
+    .await
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Serves until `shutdown` resolves. New connections are refused from then on, and the open ones
+/// get `drain_timeout` to finish before they're closed.
+async fn serve(
+    listener: TcpListener,
+    upstreams: &'static Upstreams,
+    logger: &'static Logger,
+    drain_timeout: Duration,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let mut counter = 0;
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (downstream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        handle_accept_error(err, logger).await?;
+                        continue;
+                    }
+                };
+                // Only a latency optimization, the connection works without it
+                downstream.set_nodelay(true).ok();
+                counter += 1;
+                connections.spawn(async move {
+                    let index = upstreams.pick(route(&downstream, counter).await);
+                    proxy(downstream, peer, upstreams, index, logger).await
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    time::timeout(drain_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await
+    .ok();
+
+    // Idle connections never finish on their own, so whatever is left is closed now
+    connections.shutdown().await;
+    Ok(())
+}
+
+/// The account id in an HTTP request line like `GET /clientes/1/extrato HTTP/1.1`.
+fn account_id(head: &[u8]) -> Option<u64> {
+    let line = head.split(|byte| *byte == b'\n').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.splitn(3, ' ');
+    // Without the version the path may have been cut short
+    let (_, path, _) = (parts.next()?, parts.next()?, parts.next()?);
+    let mut segments = path.split('/').skip_while(|segment| *segment != "clientes");
+    segments.nth(1)?.parse().ok()
+}
+
+/// Where to route a connection, hashing the account of its first request so every connection of
+/// an account reaches the same upstream. The request is only peeked, so it's still proxied as is.
+/// Connections without an account, or that send nothing in time, are balanced by `counter`.
+async fn route(downstream: &TcpStream, counter: usize) -> usize {
+    let mut head = [0; PEEK_SIZE];
+    let peeked = match time::timeout(PEEK_TIMEOUT, downstream.peek(&mut head)).await {
+        Ok(Ok(peeked)) => peeked,
+        _ => 0,
+    };
+    match account_id(&head[..peeked]) {
+        Some(id) => {
+            let mut hasher = DefaultHasher::new();
+            id.hash(&mut hasher);
+            hasher.finish() as usize
+        }
+        None => counter,
+    }
+}
+
+/// Pipes the connection to the upstream at `index`, or to the next ones when it can't be reached.
+/// The connection is closed when no upstream can be reached. Each connection is logged once it's
+/// closed, with how many bytes went each way.
+async fn proxy(
+    mut downstream: TcpStream,
+    peer: SocketAddr,
+    upstreams: &Upstreams,
+    index: usize,
+    logger: &Logger,
+) {
+    let started = Instant::now();
+    let addrs = &upstreams.addrs;
+    for offset in 0..addrs.len() {
+        let addr = addrs[(index + offset) % addrs.len()];
+        let mut upstream = match UnixStream::connect(addr).await {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                let fields: &[(&str, &dyn Display)] = &[("upstream", &addr), ("error", &err)];
+                logger.log(Level::Warn, "failed to connect", fields);
+                continue;
+            }
+        };
+
+        let (level, sent, received, error) =
+            match io::copy_bidirectional(&mut downstream, &mut upstream).await {
+                Ok((sent, received)) => (Level::Info, sent, received, None),
+                Err(err) => (Level::Warn, 0, 0, Some(err)),
+            };
+        if logger.enabled(level) {
+            let error = error.map_or(String::from("-"), |err| err.to_string());
+            logger.log(
+                level,
+                "access",
+                &[
+                    ("peer", &peer),
+                    ("upstream", &addr),
+                    ("sent", &sent),
+                    ("received", &received),
+                    ("latency", &format!("{:?}", started.elapsed())),
+                    ("error", &error),
+                ],
+            );
+        }
+        return;
+    }
+
+    logger.log(Level::Error, "no upstream reachable", &[("peer", &peer)]);
+    downstream.shutdown().await.ok();
+}
+
+/// Logs errors from `accept` the listener can recover from, returning the ones it can't.
+async fn handle_accept_error(err: io::Error, logger: &Logger) -> io::Result<()> {
+    match err.kind() {
+        // The peer gave up before the connection was accepted, nothing to wait for
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => Ok(()),
+        // The listener itself is broken
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotConnected => Err(err),
+        _ => {
+            logger.log(
+                Level::Error,
+                "failed to accept connection",
+                &[("error", &err)],
+            );
+            time::sleep(ACCEPT_BACKOFF).await;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::{io::AsyncReadExt, net::UnixListener, sync::oneshot};
+
+    use super::*;
+
+    async fn balancer(addrs: Vec<&'static str>) -> TcpStream {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstreams = Box::leak(Box::new(Upstreams::new(addrs)));
+        tokio::spawn(serve_forever(listener, upstreams));
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    fn serve_forever(
+        listener: TcpListener,
+        upstreams: &'static Upstreams,
+    ) -> impl Future<Output = io::Result<()>> {
+        serve(
+            listener,
+            upstreams,
+            quiet(),
+            DEFAULT_DRAIN_TIMEOUT,
+            std::future::pending(),
+        )
+    }
+
+    fn quiet() -> &'static Logger {
+        Box::leak(Box::new(Logger::new(None, std::io::sink())))
+    }
+
+    fn leak(path: std::path::PathBuf) -> &'static str {
+        Box::leak(path.to_str().unwrap().to_owned().into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn test_missing_upstream() {
+        let tmp = tempdir().unwrap();
+        let missing = leak(tmp.path().join("missing.socket"));
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_forever(
+            listener,
+            Box::leak(Box::new(Upstreams::new(vec![missing]))),
+        ));
+
+        for _ in 0..2 {
+            let mut downstream = TcpStream::connect(addr).await.unwrap();
+            assert_eq!(0, downstream.read(&mut [0; 16]).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skip_missing_upstream() {
+        let tmp = tempdir().unwrap();
+        let missing = leak(tmp.path().join("missing.socket"));
+        let live = leak(tmp.path().join("live.socket"));
+
+        let upstream = UnixListener::bind(live).unwrap();
         tokio::spawn(async move {
-            let mut upstream = UnixStream::connect(addr).await.unwrap();
-            io::copy_bidirectional(&mut downstream, &mut upstream)
-                .await
-                .unwrap();
+            while let Ok((mut socket, _)) = upstream.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    io::copy(&mut reader, &mut writer).await.ok();
+                });
+            }
         });
+
+        let mut downstream = balancer(vec![missing, live]).await;
+        downstream.write_all(b"Rinha").await.unwrap();
+        let mut echo = [0; 5];
+        downstream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(b"Rinha", &echo);
     }
 
-    Ok(())
+    #[test]
+    fn test_account_id() {
+        assert_eq!(
+            Some(1),
+            account_id(b"GET /clientes/1/extrato HTTP/1.1\r\nHost: lb\r\n")
+        );
+        assert_eq!(
+            Some(42),
+            account_id(b"POST /clientes/42/transacoes HTTP/1.1\r\n")
+        );
+        assert_eq!(None, account_id(b"GET /clientes/4"));
+        assert_eq!(None, account_id(b"GET /health HTTP/1.1\r\n"));
+        assert_eq!(None, account_id(b"\x16\x03\x01"));
+    }
+
+    #[tokio::test]
+    async fn test_route_account_to_same_upstream() {
+        let tmp = tempdir().unwrap();
+        let mut addrs = Vec::new();
+        for name in ["app1", "app2"] {
+            let addr = leak(tmp.path().join(format!("{name}.socket")));
+            let upstream = UnixListener::bind(addr).unwrap();
+            tokio::spawn(async move {
+                while let Ok((mut socket, _)) = upstream.accept().await {
+                    if socket.read(&mut [0; 64]).await.unwrap_or(0) > 0 {
+                        socket.write_all(name.as_bytes()).await.ok();
+                    }
+                }
+            });
+            addrs.push(addr);
+        }
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstreams = Box::leak(Box::new(Upstreams::new(addrs)));
+        tokio::spawn(serve_forever(listener, upstreams));
+
+        let upstream = |request: &'static str| async move {
+            let mut downstream = TcpStream::connect(addr).await.unwrap();
+            downstream.write_all(request.as_bytes()).await.unwrap();
+            let mut name = String::new();
+            downstream.read_to_string(&mut name).await.unwrap();
+            name
+        };
+
+        // Round robin alone would alternate between the upstreams
+        let extrato = upstream("GET /clientes/3/extrato HTTP/1.1\r\n\r\n").await;
+        for _ in 0..3 {
+            let transacao = upstream("POST /clientes/3/transacoes HTTP/1.1\r\n\r\n").await;
+            assert_eq!(extrato, transacao);
+        }
+
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            names.push(upstream("GET /health HTTP/1.1\r\n\r\n").await);
+        }
+        names.sort();
+        assert_eq!(vec!["app1", "app2"], names);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_idle_connection() {
+        let tmp = tempdir().unwrap();
+        let live = leak(tmp.path().join("live.socket"));
+
+        let upstream = UnixListener::bind(live).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = upstream.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    io::copy(&mut reader, &mut writer).await.ok();
+                });
+            }
+        });
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, signal) = oneshot::channel();
+        let server = tokio::spawn(serve(
+            listener,
+            Box::leak(Box::new(Upstreams::new(vec![live]))),
+            quiet(),
+            Duration::from_millis(100),
+            async {
+                signal.await.ok();
+            },
+        ));
+
+        // Proxied and then left idle, like a keep-alive connection between requests
+        let mut downstream = TcpStream::connect(addr).await.unwrap();
+        downstream.write_all(b"Rinha").await.unwrap();
+        let mut echo = [0; 5];
+        downstream.read_exact(&mut echo).await.unwrap();
+
+        shutdown.send(()).unwrap();
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(0, downstream.read(&mut [0; 16]).await.unwrap());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_skips_missing_upstream() {
+        let tmp = tempdir().unwrap();
+        let missing = leak(tmp.path().join("missing.socket"));
+        let live = leak(tmp.path().join("live.socket"));
+        let _upstream = UnixListener::bind(live).unwrap();
+
+        let upstreams = Upstreams::new(vec![live, missing]);
+        assert_eq!(
+            vec![0, 1],
+            (0..2).map(|i| upstreams.pick(i)).collect::<Vec<_>>()
+        );
+
+        upstreams.probe().await;
+        assert!((0..10).all(|i| upstreams.pick(i) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_handle_accept_error() {
+        let too_many_open_files = io::Error::from_raw_os_error(24);
+        assert!(handle_accept_error(too_many_open_files, quiet())
+            .await
+            .is_ok());
+
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert!(handle_accept_error(aborted, quiet()).await.is_ok());
+
+        let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(handle_accept_error(invalid, quiet()).await.is_err());
+    }
 }
@@ -0,0 +1,196 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use axum::http::{
+    uri::{Authority, Scheme},
+    Uri,
+};
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection, HttpConnector},
+    rt::TokioIo,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixStream},
+};
+use tower_service::Service;
+
+/// Scheme of the uris of unix socket upstreams, whose authority is the hex encoded socket path.
+const UNIX_SCHEME: &str = "unix";
+
+/// Points `uri` to the upstream at `addr`. Addresses with a slash are unix socket paths, which
+/// can't be an authority, so they're hex encoded under the `unix` scheme for `UpstreamConnector`.
+pub fn upstream_uri(addr: &str, uri: Uri) -> Option<Uri> {
+    let mut parts = uri.into_parts();
+    if addr.contains('/') {
+        let hex = addr
+            .bytes()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        parts.scheme = Some(Scheme::from_str(UNIX_SCHEME).ok()?);
+        parts.authority = Some(Authority::from_str(&hex).ok()?);
+    } else {
+        parts.scheme = Some(Scheme::HTTP);
+        parts.authority = Some(Authority::from_str(addr).ok()?);
+    }
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some("/".parse().ok()?);
+    }
+    Uri::from_parts(parts).ok()
+}
+
+/// The socket path of a uri built by `upstream_uri` for a unix socket upstream.
+fn socket_path(uri: &Uri) -> Option<String> {
+    let hex = uri.host()?.as_bytes();
+    let bytes = hex
+        .chunks(2)
+        .map(|byte| u8::from_str_radix(std::str::from_utf8(byte).ok()?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Connects to TCP upstreams with an `HttpConnector`, and to unix socket ones directly.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+}
+
+impl UpstreamConnector {
+    pub fn new(http: HttpConnector) -> Self {
+        Self { http }
+    }
+}
+
+impl Default for UpstreamConnector {
+    fn default() -> Self {
+        Self::new(HttpConnector::new())
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TokioIo<UpstreamStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.http.poll_ready(cx).map_err(io::Error::other)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            return Box::pin(async move {
+                let path = socket_path(&uri).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "invalid unix socket uri")
+                })?;
+                let stream = UnixStream::connect(path).await?;
+                Ok(TokioIo::new(UpstreamStream::Unix(stream)))
+            });
+        }
+
+        let connecting = self.http.call(uri);
+        Box::pin(async move {
+            let stream = connecting.await.map_err(io::Error::other)?;
+            Ok(TokioIo::new(UpstreamStream::Tcp(stream.into_inner())))
+        })
+    }
+}
+
+/// A connection to either kind of upstream.
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp(stream) => stream.connected(),
+            Self::Unix(stream) => stream.connected(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_uri() {
+        let uri = Uri::from_static("/clientes/1/extrato?limit=10");
+        assert_eq!(
+            "http://app1:9997/clientes/1/extrato?limit=10",
+            upstream_uri("app1:9997", uri.clone()).unwrap().to_string()
+        );
+
+        let unix = upstream_uri("/tmp/app 1.socket", uri).unwrap();
+        assert_eq!(Some(UNIX_SCHEME), unix.scheme_str());
+        assert_eq!(
+            "/clientes/1/extrato?limit=10",
+            unix.path_and_query().unwrap()
+        );
+        assert_eq!(Some(String::from("/tmp/app 1.socket")), socket_path(&unix));
+    }
+}
@@ -1,65 +1,408 @@
 use std::{
+    collections::HashMap,
     env,
     hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
     str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use axum::{
     body::Body,
-    extract::{Request, State},
-    handler::Handler,
-    http::{
-        uri::{Authority, Scheme},
-        StatusCode, Uri,
-    },
-    response::IntoResponse,
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Router,
 };
+use http_body_util::LengthLimitError;
 use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::TokioExecutor,
 };
-use tokio::net::TcpListener;
+use rinha::{Level, Logger, METRICS, METRICS_CONTENT_TYPE};
+use tokio::{net::TcpListener, time};
+
+use crate::connector::{upstream_uri, UpstreamConnector};
+
+mod connector;
+
+/// How many upstreams the proxy tries to connect to before answering with a bad gateway.
+const MAX_ATTEMPTS: usize = 3;
+
+/// How long the proxy waits for an upstream response when `UPSTREAM_TIMEOUT` isn't set.
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request body the proxy buffers when `MAX_BODY_SIZE` isn't set, plenty for the API.
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Cookie naming the upstream a client sticks to when sticky sessions are enabled.
+const STICKY_COOKIE: &str = "lb_upstream";
 
 #[derive(Clone)]
 struct AppState {
+    pool: Arc<RwLock<Pool>>,
+    /// Builds the pool for a new set of upstreams, as changed through the admin API.
+    build_pool: Arc<BuildPool>,
+    http_client: Client<UpstreamConnector, Body>,
+    upstream_timeout: Duration,
+    sticky_sessions: bool,
+    /// Requests with larger bodies are answered with 413 instead of being buffered.
+    max_body_size: usize,
+}
+
+type BuildPool = dyn Fn(&[(String, usize)]) -> Pool + Send + Sync;
+
+/// The upstreams requests are balanced over, with their weights, balancer and health. Changing
+/// the upstreams replaces the whole pool, so each request works with a consistent snapshot.
+#[derive(Clone)]
+struct Pool {
+    upstreams: Vec<(String, usize)>,
     load_balancer: Arc<dyn LoadBalancer + Send + Sync>,
-    http_client: Client<HttpConnector, Body>,
+    health: Arc<Health>,
+}
+
+impl Pool {
+    fn addrs(&self) -> Vec<String> {
+        self.upstreams
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Whether `addr` is one of the upstreams and is available.
+    fn is_available(&self, addr: &str) -> bool {
+        let addrs = self.addrs();
+        addrs
+            .iter()
+            .position(|candidate| candidate == addr)
+            .is_some_and(|index| self.health.is_available(&addrs, index))
+    }
+}
+
+/// Whether each upstream passed its last health check and, with a circuit breaker, isn't failing
+/// the requests sent to it. Upstreams start as up, and stay so when health checks are disabled.
+struct Health {
+    up: Vec<AtomicBool>,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl Health {
+    fn new(upstreams: usize) -> Self {
+        Self {
+            up: (0..upstreams).map(|_| AtomicBool::new(true)).collect(),
+            circuit_breaker: None,
+        }
+    }
+
+    fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    fn is_available(&self, addrs: &[String], index: usize) -> bool {
+        self.up[index].load(Ordering::Relaxed)
+            && self
+                .circuit_breaker
+                .as_ref()
+                .is_none_or(|circuit_breaker| circuit_breaker.allows(&addrs[index]))
+    }
+
+    /// Picks the upstream at `index`, or the next available one after it. When no upstream is
+    /// available the one at `index` is picked anyway.
+    fn pick<'a>(&self, addrs: &'a [String], index: usize) -> &'a str {
+        (0..addrs.len())
+            .map(|offset| (index + offset) % addrs.len())
+            .find(|index| self.is_available(addrs, *index))
+            .map(|index| addrs[index].as_str())
+            .unwrap_or(&addrs[index % addrs.len()])
+    }
+
+    /// Tells the circuit breaker, if any, how a request sent to `addr` went.
+    fn record(&self, addr: &str, success: bool) {
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker.record(addr, success);
+        }
+    }
+
+    /// Requests `path` from every upstream, marking as down the ones that fail or take longer
+    /// than `timeout` to answer.
+    async fn check(
+        &self,
+        client: &Client<UpstreamConnector, Body>,
+        addrs: &[String],
+        path: &str,
+        timeout: Duration,
+    ) {
+        for (index, addr) in addrs.iter().enumerate() {
+            let uri = Uri::from_str(path).ok();
+            let up = match uri.and_then(|uri| upstream_uri(addr, uri)) {
+                Some(uri) => matches!(
+                    time::timeout(timeout, client.get(uri)).await,
+                    Ok(Ok(res)) if res.status().is_success()
+                ),
+                None => false,
+            };
+            self.up[index].store(up, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stops sending requests to an upstream after `threshold` of them fail in a row. Once `cooldown`
+/// passes the upstream is half open: requests reach it again, and the first one to fail opens the
+/// circuit for another cooldown, while one success closes it.
+struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    circuits: HashMap<String, Mutex<Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: usize,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(addrs: &[String], threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            circuits: addrs
+                .iter()
+                .map(|addr| (addr.clone(), Mutex::default()))
+                .collect(),
+        }
+    }
+
+    fn allows(&self, addr: &str) -> bool {
+        match self.circuits.get(addr) {
+            Some(circuit) => match circuit.lock().unwrap().opened_at {
+                Some(opened_at) => opened_at.elapsed() >= self.cooldown,
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    fn record(&self, addr: &str, success: bool) {
+        let Some(circuit) = self.circuits.get(addr) else {
+            return;
+        };
+        let mut circuit = circuit.lock().unwrap();
+
+        if success {
+            *circuit = Circuit::default();
+            return;
+        }
+
+        circuit.failures += 1;
+        if circuit.failures >= self.threshold {
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
 }
 
 struct RoundRobin {
     addrs: Vec<String>,
     req_counter: Arc<AtomicUsize>,
+    health: Arc<Health>,
 }
 
 trait LoadBalancer {
     fn next_server(&self, req: &Request) -> String;
+
+    /// Called by the proxy right before a request is sent to `addr`.
+    fn request_started(&self, _addr: &str) {}
+
+    /// Called by the proxy once a request to `addr` got a response or failed.
+    fn request_finished(&self, _addr: &str) {}
 }
 
 impl LoadBalancer for RoundRobin {
     fn next_server(&self, _req: &Request) -> String {
         let count = self.req_counter.fetch_add(1, Ordering::Relaxed);
-        self.addrs[count % self.addrs.len()].clone()
+        self.health.pick(&self.addrs, count).to_owned()
+    }
+}
+
+/// Round robin where each upstream gets as many requests per cycle as its weight, spread over the
+/// cycle instead of in a row.
+struct WeightedRoundRobin {
+    addrs: Vec<String>,
+    schedule: Vec<usize>,
+    req_counter: AtomicUsize,
+    health: Arc<Health>,
+}
+
+impl WeightedRoundRobin {
+    fn new(upstreams: Vec<(String, usize)>, health: Arc<Health>) -> Self {
+        let total = upstreams.iter().map(|(_, weight)| weight).sum::<usize>();
+
+        // Smooth weighted round robin, as done by nginx: every turn each upstream earns its weight
+        // and the richest one is picked, paying back the total
+        let mut credits = vec![0; upstreams.len()];
+        let schedule = (0..total)
+            .map(|_| {
+                for (credit, (_, weight)) in credits.iter_mut().zip(&upstreams) {
+                    *credit += *weight as isize;
+                }
+                let (picked, _) = credits
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .max_by_key(|(_, credit)| **credit)
+                    .unwrap();
+                credits[picked] -= total as isize;
+                picked
+            })
+            .collect();
+
+        Self {
+            addrs: upstreams.into_iter().map(|(addr, _)| addr).collect(),
+            schedule,
+            req_counter: AtomicUsize::new(0),
+            health,
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn next_server(&self, _req: &Request) -> String {
+        let count = self.req_counter.fetch_add(1, Ordering::Relaxed);
+        let index = self.schedule[count % self.schedule.len()];
+        self.health.pick(&self.addrs, index).to_owned()
+    }
+}
+
+/// Sends each request to the upstream with the fewest requests in flight.
+struct LeastConnections {
+    addrs: Vec<String>,
+    in_flight: Vec<AtomicUsize>,
+    health: Arc<Health>,
+}
+
+impl LeastConnections {
+    fn new(addrs: Vec<String>, health: Arc<Health>) -> Self {
+        Self {
+            in_flight: addrs.iter().map(|_| AtomicUsize::new(0)).collect(),
+            addrs,
+            health,
+        }
+    }
+
+    fn in_flight(&self, addr: &str) -> Option<&AtomicUsize> {
+        let index = self.addrs.iter().position(|candidate| candidate == addr)?;
+        Some(&self.in_flight[index])
+    }
+}
+
+impl LoadBalancer for LeastConnections {
+    fn next_server(&self, _req: &Request) -> String {
+        let least_busy = (0..self.addrs.len())
+            .min_by_key(|index| {
+                let down = !self.health.is_available(&self.addrs, *index);
+                (down, self.in_flight[*index].load(Ordering::Relaxed))
+            })
+            .unwrap_or(0);
+        self.addrs[least_busy].clone()
+    }
+
+    fn request_started(&self, addr: &str) {
+        if let Some(in_flight) = self.in_flight(addr) {
+            in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn request_finished(&self, addr: &str) {
+        if let Some(in_flight) = self.in_flight(addr) {
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
     }
 }
 
 struct RinhaAccountBalancer {
     addrs: Vec<String>,
+    health: Arc<Health>,
+}
+
+/// The account id of paths like `/clientes/1/extrato`.
+fn account_id(path: &str) -> Option<u64> {
+    let mut segments = path.split('/').skip_while(|segment| *segment != "clientes");
+    segments.nth(1)?.parse().ok()
+}
+
+/// Hash of the account a request belongs to, or of its whole path when there's no account, so
+/// every request of an account is routed the same way.
+fn route_hash(req: &Request) -> u64 {
+    let path = req.uri().path();
+    let mut hasher = DefaultHasher::new();
+    match account_id(path) {
+        Some(id) => id.hash(&mut hasher),
+        None => path.hash(&mut hasher),
+    }
+    hasher.finish()
 }
 
 impl LoadBalancer for RinhaAccountBalancer {
     fn next_server(&self, req: &Request) -> String {
-        let path = req.uri().path();
-        let hash = {
-            let mut hasher = DefaultHasher::new();
-            path.hash(&mut hasher);
-            hasher.finish() as usize
-        };
-        self.addrs[hash % self.addrs.len()].to_string()
+        let hash = route_hash(req) as usize;
+        self.health.pick(&self.addrs, hash).to_owned()
+    }
+}
+
+/// Routes accounts over a hash ring where each upstream owns many points. Unlike the `hash % len`
+/// of `RinhaAccountBalancer`, adding or removing an upstream only moves the accounts landing on
+/// the points it owns.
+struct ConsistentHash {
+    addrs: Vec<String>,
+    /// Points of the ring, sorted, with the index of the upstream owning each one.
+    ring: Vec<(u64, usize)>,
+    health: Arc<Health>,
+}
+
+impl ConsistentHash {
+    /// How many points each upstream owns, enough to spread the accounts evenly among them.
+    const VIRTUAL_NODES: usize = 160;
+
+    fn new(addrs: Vec<String>, health: Arc<Health>) -> Self {
+        let mut ring = addrs
+            .iter()
+            .enumerate()
+            .flat_map(|(index, addr)| {
+                (0..Self::VIRTUAL_NODES).map(move |node| {
+                    let mut hasher = DefaultHasher::new();
+                    (addr, node).hash(&mut hasher);
+                    (hasher.finish(), index)
+                })
+            })
+            .collect::<Vec<_>>();
+        ring.sort_unstable();
+
+        Self {
+            addrs,
+            ring,
+            health,
+        }
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn next_server(&self, req: &Request) -> String {
+        // The first point from the hash on, walking the ring past unavailable upstreams
+        let hash = route_hash(req);
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        let owner = |offset: usize| self.ring[(start + offset) % self.ring.len()].1;
+        let index = (0..self.ring.len())
+            .map(owner)
+            .find(|index| self.health.is_available(&self.addrs, *index))
+            .unwrap_or_else(|| owner(0));
+        self.addrs[index].clone()
     }
 }
 
@@ -70,19 +413,18 @@ async fn main() {
         .and_then(|port| port.parse::<u16>().ok())
         .unwrap_or(9999);
 
-    let addrs = env::var("UPSTREAMS")
+    let upstreams = env::var("UPSTREAMS")
         .ok()
         .map(|upstream| {
             upstream
                 .split(',')
-                .map(|addr| addr.trim().to_owned())
-                .collect::<Vec<String>>()
+                .map(|upstream| parse_upstream(upstream.trim()))
+                .collect::<Vec<_>>()
         })
         .unwrap_or(vec![
-            String::from("0.0.0.0:9997"),
-            String::from("0.0.0.0:9998"),
+            (String::from("0.0.0.0:9997"), 1),
+            (String::from("0.0.0.0:9998"), 1),
         ]);
-
     let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
 
     let client = {
@@ -91,51 +433,1040 @@ async fn main() {
         connector.set_nodelay(true);
         Client::builder(TokioExecutor::new())
             .http2_only(true)
-            .build::<_, Body>(connector)
+            .build::<_, Body>(UpstreamConnector::new(connector))
     };
 
-    #[allow(unused)]
-    let round_robin = RoundRobin {
-        addrs: addrs.clone(),
-        req_counter: Arc::new(AtomicUsize::new(0)),
-    };
+    let circuit_breaker_threshold = env::var("CIRCUIT_BREAKER_THRESHOLD")
+        .ok()
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(5);
+    let circuit_breaker_cooldown = env::var("CIRCUIT_BREAKER_COOLDOWN")
+        .ok()
+        .and_then(|cooldown| humantime::parse_duration(&cooldown).ok())
+        .unwrap_or(Duration::from_secs(1));
+
+    // One of round-robin, weighted-round-robin, least-connections, account or consistent-hash
+    let strategy = env::var("LOAD_BALANCER").unwrap_or(String::from("round-robin"));
 
-    #[allow(unused)]
-    let fixed_load_balancer = RinhaAccountBalancer {
-        addrs: addrs.clone(),
+    let build_pool = move |upstreams: &[(String, usize)]| {
+        let addrs = upstreams
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        let circuit_breaker =
+            CircuitBreaker::new(&addrs, circuit_breaker_threshold, circuit_breaker_cooldown);
+        let health = Arc::new(Health::new(addrs.len()).with_circuit_breaker(circuit_breaker));
+
+        let load_balancer: Arc<dyn LoadBalancer + Send + Sync> = match strategy.as_str() {
+            "weighted-round-robin" => {
+                Arc::new(WeightedRoundRobin::new(upstreams.to_vec(), health.clone()))
+            }
+            "least-connections" => Arc::new(LeastConnections::new(addrs, health.clone())),
+            "account" => Arc::new(RinhaAccountBalancer {
+                addrs,
+                health: health.clone(),
+            }),
+            "consistent-hash" => Arc::new(ConsistentHash::new(addrs, health.clone())),
+            _ => Arc::new(RoundRobin {
+                addrs,
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: health.clone(),
+            }),
+        };
+
+        Pool {
+            upstreams: upstreams.to_vec(),
+            load_balancer,
+            health,
+        }
     };
+    let pool = Arc::new(RwLock::new(build_pool(&upstreams)));
+
+    let health_check_interval = env::var("HEALTH_CHECK_INTERVAL")
+        .ok()
+        .and_then(|interval| humantime::parse_duration(&interval).ok());
+
+    if let Some(interval) = health_check_interval {
+        let path = env::var("HEALTH_CHECK_PATH").unwrap_or(String::from("/health"));
+        let (pool, client) = (pool.clone(), client.clone());
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let pool = pool.read().unwrap().clone();
+                pool.health
+                    .check(&client, &pool.addrs(), &path, interval)
+                    .await;
+            }
+        });
+    }
 
     let app_state = AppState {
-        load_balancer: Arc::new(round_robin),
+        pool,
+        build_pool: Arc::new(build_pool),
         http_client: client,
+        upstream_timeout: env::var("UPSTREAM_TIMEOUT")
+            .ok()
+            .and_then(|timeout| humantime::parse_duration(&timeout).ok())
+            .unwrap_or(DEFAULT_UPSTREAM_TIMEOUT),
+        sticky_sessions: env::var("STICKY_SESSIONS")
+            .ok()
+            .and_then(|sticky| sticky.parse::<bool>().ok())
+            .unwrap_or(false),
+        max_body_size: env::var("MAX_BODY_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE),
     };
 
-    let app = proxy.with_state(app_state);
+    // The admin API is off unless given a port of its own, only reachable from the same host
+    let admin_port = env::var("ADMIN_PORT")
+        .ok()
+        .and_then(|port| port.parse::<u16>().ok());
+    if let Some(admin_port) = admin_port {
+        let listener = TcpListener::bind(("127.0.0.1", admin_port)).await.unwrap();
+        let admin = admin(app_state.clone());
+        tokio::spawn(async move { axum::serve(listener, admin).await.unwrap() });
+    }
+
+    let app = app(app_state, Arc::new(Logger::from_env()));
 
     println!("HTTP lb ({}) ready 9999", env!("CARGO_PKG_VERSION"));
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// The public routes, where everything but the metrics is proxied.
+fn app(state: AppState, logger: Arc<Logger>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .fallback(proxy)
+        .layer(middleware::from_fn_with_state(logger, access_log))
+        .with_state(state)
+}
+
+/// Routes to change the upstreams, served on their own port so clients can't reach them.
+fn admin(state: AppState) -> Router {
+    Router::new()
+        .route("/admin/upstreams", post(add_upstream))
+        .route("/admin/upstreams/:addr", delete(remove_upstream))
+        .with_state(state)
+}
+
+/// Splits an upstream like `0.0.0.0:9997:3` into its address and weight. As addresses already
+/// have a port, the weight is only taken from a second colon, defaulting to 1. Addresses with a
+/// slash are unix socket paths, like `/app/rinha-app1.socket:3`, and have no port.
+fn parse_upstream(upstream: &str) -> (String, usize) {
+    match upstream.rsplit_once(':') {
+        Some((addr, weight)) if addr.contains(':') || addr.contains('/') => match weight.parse() {
+            Ok(weight) if weight > 0 => (addr.to_owned(), weight),
+            _ => (upstream.to_owned(), 1),
+        },
+        _ => (upstream.to_owned(), 1),
+    }
+}
+
+/// Reports a request as finished to the load balancer when dropped, so it's counted even when the
+/// request fails or the client goes away mid-request.
+struct InFlight<'a> {
+    load_balancer: &'a (dyn LoadBalancer + Send + Sync),
+    addr: &'a str,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(load_balancer: &'a (dyn LoadBalancer + Send + Sync), addr: &'a str) -> Self {
+        load_balancer.request_started(addr);
+        Self {
+            load_balancer,
+            addr,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.load_balancer.request_finished(self.addr);
+    }
+}
+
+/// How many requests went to each upstream, served by the balancer itself rather than proxied.
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)],
+        METRICS.render(),
+    )
+}
+
+/// The upstream a request was proxied to, kept in the response extensions for the access log.
+#[derive(Debug, Clone)]
+struct Upstream(String);
+
+/// Logs every request, with the upstream it was proxied to if any. Server errors are logged as
+/// warnings, everything else as info.
+async fn access_log(State(logger): State<Arc<Logger>>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let started = Instant::now();
+
+    let res = next.run(req).await;
+
+    let status = res.status();
+    let level = if status.is_server_error() {
+        Level::Warn
+    } else {
+        Level::Info
+    };
+    if logger.enabled(level) {
+        let upstream = res.extensions().get::<Upstream>().map_or("-", |u| &u.0);
+        logger.log(
+            level,
+            "access",
+            &[
+                ("method", &method),
+                ("path", &path),
+                ("upstream", &upstream),
+                ("status", &status.as_u16()),
+                ("latency", &format!("{:?}", started.elapsed())),
+            ],
+        );
+    }
+    res
+}
+
+/// Appends the address of the client to `X-Forwarded-For`, after the ones of any proxies before
+/// this one, and tells upstreams it came over plain HTTP unless a proxy before already did.
+fn forward_headers(headers: &mut HeaderMap, peer: SocketAddr) {
+    let forwarded_for = match headers.get("x-forwarded-for").map(HeaderValue::to_str) {
+        Some(Ok(forwarded_for)) => format!("{forwarded_for}, {}", peer.ip()),
+        _ => peer.ip().to_string(),
+    };
+    if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", forwarded_for);
+    }
+    headers
+        .entry("x-forwarded-proto")
+        .or_insert(HeaderValue::from_static("http"));
+}
+
+/// Adds an upstream, given in the body like the ones in `UPSTREAMS`. Every upstream is considered
+/// healthy again afterwards.
+async fn add_upstream(State(state): State<AppState>, body: String) -> StatusCode {
+    let (addr, weight) = parse_upstream(body.trim());
+    if addr.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let mut pool = state.pool.write().unwrap();
+    if pool.upstreams.iter().any(|(existing, _)| *existing == addr) {
+        return StatusCode::CONFLICT;
+    }
+
+    let mut upstreams = pool.upstreams.clone();
+    upstreams.push((addr, weight));
+    *pool = (state.build_pool)(&upstreams);
+    StatusCode::CREATED
+}
+
+/// Stops sending new requests to an upstream, while the ones in flight finish. The last upstream
+/// can't be removed.
+async fn remove_upstream(State(state): State<AppState>, Path(addr): Path<String>) -> StatusCode {
+    let mut pool = state.pool.write().unwrap();
+    let upstreams = pool
+        .upstreams
+        .iter()
+        .filter(|(existing, _)| *existing != addr)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if upstreams.len() == pool.upstreams.len() {
+        return StatusCode::NOT_FOUND;
+    }
+    if upstreams.is_empty() {
+        return StatusCode::CONFLICT;
+    }
+
+    *pool = (state.build_pool)(&upstreams);
+    StatusCode::NO_CONTENT
+}
+
+/// The upstream named by the sticky session cookie, if any.
+fn sticky_upstream(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == STICKY_COOKIE)
+        .map(|(_, addr)| addr)
 }
 
 async fn proxy(
     State(AppState {
-        load_balancer,
+        pool,
         http_client,
+        upstream_timeout,
+        sticky_sessions,
+        max_body_size,
+        ..
     }): State<AppState>,
-    mut req: Request,
-) -> impl IntoResponse {
-    let addr = load_balancer.next_server(&req);
-
-    *req.uri_mut() = {
-        let uri = req.uri();
-        let mut parts = uri.clone().into_parts();
-        parts.authority = Authority::from_str(addr.as_str()).ok();
-        parts.scheme = Some(Scheme::HTTP);
-        Uri::from_parts(parts).unwrap()
-    };
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+) -> Result<Response, StatusCode> {
+    let pool = pool.read().unwrap().clone();
+
+    // Kept around to be sent again when an upstream can't be reached
+    let (mut parts, body) = req.into_parts();
+    forward_headers(&mut parts.headers, peer);
+
+    // Sticky clients go back to their upstream while it's available, otherwise they're balanced
+    // as usual and stick to the new one
+    let sticky = sticky_upstream(&parts.headers)
+        .filter(|_| sticky_sessions)
+        .map(str::to_owned);
+    let mut sticky_addr = sticky.clone().filter(|addr| pool.is_available(addr));
+    let Pool {
+        load_balancer,
+        health,
+        ..
+    } = pool;
+    let body = axum::body::to_bytes(body, max_body_size)
+        .await
+        .map_err(|err| {
+            if err.into_inner().is::<LengthLimitError>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            }
+        })?;
+
+    for _ in 0..MAX_ATTEMPTS {
+        let mut req = Request::new(Body::from(body.clone()));
+        *req.method_mut() = parts.method.clone();
+        *req.version_mut() = parts.version;
+        *req.headers_mut() = parts.headers.clone();
+        // Balancers route by the original path, before it's pointed at the upstream
+        *req.uri_mut() = parts.uri.clone();
+
+        let addr = match sticky_addr.take() {
+            Some(addr) => addr,
+            None => load_balancer.next_server(&req),
+        };
+        METRICS.increment("rinha_lb_upstream_requests_total", &[("upstream", &addr)]);
+
+        *req.uri_mut() = upstream_uri(&addr, parts.uri.clone()).ok_or(StatusCode::BAD_GATEWAY)?;
+
+        let _in_flight = InFlight::start(load_balancer.as_ref(), &addr);
+        let Ok(res) = time::timeout(upstream_timeout, http_client.request(req)).await else {
+            health.record(&addr, false);
+            let mut res = StatusCode::GATEWAY_TIMEOUT.into_response();
+            res.extensions_mut().insert(Upstream(addr.clone()));
+            return Ok(res);
+        };
+        health.record(&addr, res.is_ok());
+        match res {
+            Ok(mut res) => {
+                if sticky_sessions && sticky.as_ref() != Some(&addr) {
+                    let cookie = format!("{STICKY_COOKIE}={addr}; Path=/; HttpOnly");
+                    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                        res.headers_mut().append(header::SET_COOKIE, cookie);
+                    }
+                }
+                res.extensions_mut().insert(Upstream(addr.clone()));
+                return Ok(res.into_response());
+            }
+            // Nothing was sent yet, so it's safe to try another upstream
+            Err(err) if err.is_connect() => continue,
+            Err(_) => return Err(StatusCode::BAD_GATEWAY),
+        }
+    }
+
+    Err(StatusCode::BAD_GATEWAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    fn app_state(
+        load_balancer: Arc<dyn LoadBalancer + Send + Sync>,
+        health: Arc<Health>,
+        http_client: Client<UpstreamConnector, Body>,
+        upstream_timeout: Duration,
+    ) -> AppState {
+        AppState {
+            pool: Arc::new(RwLock::new(Pool {
+                upstreams: Vec::new(),
+                load_balancer,
+                health,
+            })),
+            build_pool: Arc::new(round_robin_pool),
+            http_client,
+            upstream_timeout,
+            sticky_sessions: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    fn round_robin_pool(upstreams: &[(String, usize)]) -> Pool {
+        let addrs = upstreams.iter().map(|(addr, _)| addr.clone()).collect();
+        let health = Arc::new(Health::new(upstreams.len()));
+        Pool {
+            upstreams: upstreams.to_vec(),
+            load_balancer: Arc::new(RoundRobin {
+                addrs,
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: health.clone(),
+            }),
+            health,
+        }
+    }
+
+    /// Address of the client the proxied requests come from.
+    fn client() -> ConnectInfo<SocketAddr> {
+        ConnectInfo(SocketAddr::from(([192, 0, 2, 7], 40000)))
+    }
+
+    async fn upstream(status: StatusCode) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route("/health", get(move || async move { status }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_route_around_unhealthy_upstream() {
+        let addrs = vec![
+            upstream(StatusCode::OK).await,
+            upstream(StatusCode::INTERNAL_SERVER_ERROR).await,
+            upstream(StatusCode::OK).await,
+        ];
+        let client = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build::<_, Body>(UpstreamConnector::default());
+
+        let health = Arc::new(Health::new(addrs.len()));
+        let round_robin = RoundRobin {
+            addrs: addrs.clone(),
+            req_counter: Arc::new(AtomicUsize::new(0)),
+            health: health.clone(),
+        };
+
+        let req = Request::new(Body::empty());
+        let picked = (0..3)
+            .map(|_| round_robin.next_server(&req))
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, picked);
+
+        health
+            .check(&client, &addrs, "/health", Duration::from_secs(1))
+            .await;
+        let picked = (0..4)
+            .map(|_| round_robin.next_server(&req))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![&addrs[0], &addrs[2], &addrs[2], &addrs[0]],
+            picked.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_pick_when_every_upstream_is_down() {
+        let addrs = vec![String::from("a"), String::from("b")];
+        let health = Health::new(addrs.len());
+        for up in &health.up {
+            up.store(false, Ordering::Relaxed);
+        }
+        assert_eq!("b", health.pick(&addrs, 3));
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            (String::from("app1:9997"), 3),
+            parse_upstream("app1:9997:3")
+        );
+        assert_eq!((String::from("app1:9997"), 1), parse_upstream("app1:9997"));
+        assert_eq!((String::from("app1"), 1), parse_upstream("app1"));
+        assert_eq!(
+            (String::from("./app1.socket"), 2),
+            parse_upstream("./app1.socket:2")
+        );
+        assert_eq!(
+            (String::from("/app/app1.socket"), 1),
+            parse_upstream("/app/app1.socket")
+        );
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let upstreams = vec![(String::from("app1"), 3), (String::from("app2"), 1)];
+        let balancer = WeightedRoundRobin::new(upstreams, Arc::new(Health::new(2)));
+
+        let req = Request::new(Body::empty());
+        let picked = (0..400)
+            .map(|_| balancer.next_server(&req))
+            .collect::<Vec<_>>();
+        assert_eq!(300, picked.iter().filter(|addr| *addr == "app1").count());
+        assert_eq!(100, picked.iter().filter(|addr| *addr == "app2").count());
+        assert_eq!(vec!["app1", "app1", "app2", "app1"], picked[..4]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let addrs = vec![String::from("app1"), String::from("app2")];
+        let balancer = LeastConnections::new(addrs, Arc::new(Health::new(2)));
+        let req = Request::new(Body::empty());
+
+        assert_eq!("app1", balancer.next_server(&req));
+        balancer.request_started("app1");
+        assert_eq!("app2", balancer.next_server(&req));
+        assert_eq!("app2", balancer.next_server(&req));
+
+        balancer.request_started("app2");
+        balancer.request_started("app2");
+        assert_eq!("app1", balancer.next_server(&req));
+
+        balancer.request_finished("app2");
+        balancer.request_finished("app2");
+        assert_eq!("app2", balancer.next_server(&req));
+    }
+
+    #[tokio::test]
+    async fn test_least_connections_counts_failed_requests() {
+        // Nothing listens on the discard port, so the request fails
+        let addrs = vec![String::from("127.0.0.1:9")];
+        let balancer = Arc::new(LeastConnections::new(addrs, Arc::new(Health::new(1))));
+        let state = app_state(
+            balancer.clone(),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new()).build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let res = proxy(State(state), client(), Request::new(Body::empty()))
+            .await
+            .into_response();
+        assert_eq!(StatusCode::BAD_GATEWAY, res.status());
+        assert_eq!(0, balancer.in_flight[0].load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_retry_next_upstream() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let live = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route("/", axum::routing::post(|body: String| async { body }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![String::from("127.0.0.1:9"), live],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(2)),
+            }),
+            Arc::new(Health::new(2)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .body(Body::from("Rinha"))
+            .unwrap();
+        let res = proxy(State(state), client(), req).await.into_response();
+        assert_eq!(StatusCode::OK, res.status());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("Rinha", body);
+    }
+
+    #[tokio::test]
+    async fn test_reject_large_body() {
+        let mut state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![String::from("127.0.0.1:9")],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(1)),
+            }),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new()).build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+        state.max_body_size = 16;
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/clientes/1/transacoes")
+            .body(Body::from(vec![b'x'; 17]))
+            .unwrap();
+        let res = proxy(State(state), client(), req).await.into_response();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let slow = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route(
+            "/",
+            get(|| async {
+                time::sleep(Duration::from_secs(1)).await;
+                "Rinha"
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![slow],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(1)),
+            }),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            Duration::from_millis(50),
+        );
+
+        let started = Instant::now();
+        let res = proxy(State(state), client(), Request::new(Body::empty()))
+            .await
+            .into_response();
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, res.status());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                let header = |name| headers.get(name).unwrap().to_str().unwrap().to_owned();
+                format!(
+                    "{} {}",
+                    header("x-forwarded-for"),
+                    header("x-forwarded-proto")
+                )
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![addr],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(1)),
+            }),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let forwarded = |req: Request| {
+            let state = state.clone();
+            async move {
+                let res = proxy(State(state), client(), req).await.into_response();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX);
+                body.await.unwrap()
+            }
+        };
+
+        let body = forwarded(Request::new(Body::empty())).await;
+        assert_eq!("192.0.2.7 http", body);
+
+        let req = Request::builder()
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("x-forwarded-proto", "https")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!("10.0.0.1, 192.0.2.7 https", forwarded(req).await);
+    }
+
+    #[tokio::test]
+    async fn test_admin_not_public() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let upstream = Router::new().fallback(|uri: Uri| async move { uri.path().to_owned() });
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let state = AppState {
+            pool: Arc::new(RwLock::new(round_robin_pool(&[(addr.clone(), 1)]))),
+            build_pool: Arc::new(round_robin_pool),
+            http_client: Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            sticky_sessions: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        };
+        let mut app = app(state.clone(), Arc::new(Logger::new(None, std::io::sink())));
+
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/admin/upstreams")
+            .body(Body::from("127.0.0.1:9"))
+            .unwrap();
+        req.extensions_mut().insert(client());
+        let res = tower_service::Service::call(&mut app, req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!("/admin/upstreams", body);
+        assert_eq!(vec![addr], state.pool.read().unwrap().addrs());
+    }
+
+    #[tokio::test]
+    async fn test_admin_upstreams() {
+        let mut addrs = Vec::new();
+        for name in ["first", "second"] {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            addrs.push(listener.local_addr().unwrap().to_string());
+            let app = Router::new().route("/", get(move || async move { name }));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+        }
+
+        let state = AppState {
+            pool: Arc::new(RwLock::new(round_robin_pool(&[(addrs[0].clone(), 1)]))),
+            build_pool: Arc::new(round_robin_pool),
+            http_client: Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            sticky_sessions: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        };
+
+        let responses = |count| {
+            let state = state.clone();
+            async move {
+                let mut bodies = Vec::new();
+                for _ in 0..count {
+                    let res = proxy(State(state.clone()), client(), Request::new(Body::empty()))
+                        .await
+                        .into_response();
+                    let body = axum::body::to_bytes(res.into_body(), usize::MAX);
+                    bodies.push(body.await.unwrap());
+                }
+                bodies
+            }
+        };
+        assert_eq!(vec!["first", "first"], responses(2).await);
+
+        let added = add_upstream(State(state.clone()), addrs[1].clone()).await;
+        assert_eq!(StatusCode::CREATED, added);
+        let duplicated = add_upstream(State(state.clone()), addrs[1].clone()).await;
+        assert_eq!(StatusCode::CONFLICT, duplicated);
+        assert_eq!(vec!["first", "second"], responses(2).await);
+
+        let removed = remove_upstream(State(state.clone()), Path(addrs[0].clone())).await;
+        assert_eq!(StatusCode::NO_CONTENT, removed);
+        assert_eq!(vec!["second", "second"], responses(2).await);
+
+        let unknown = remove_upstream(State(state.clone()), Path(addrs[0].clone())).await;
+        assert_eq!(StatusCode::NOT_FOUND, unknown);
+        let last = remove_upstream(State(state.clone()), Path(addrs[1].clone())).await;
+        assert_eq!(StatusCode::CONFLICT, last);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_sessions() {
+        let mut addrs = Vec::new();
+        for name in ["first", "second"] {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            addrs.push(listener.local_addr().unwrap().to_string());
+            let app = Router::new().route("/", get(move || async move { name }));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+        }
+
+        let upstreams = addrs
+            .iter()
+            .map(|addr| (addr.clone(), 1))
+            .collect::<Vec<_>>();
+        let state = AppState {
+            pool: Arc::new(RwLock::new(round_robin_pool(&upstreams))),
+            build_pool: Arc::new(round_robin_pool),
+            http_client: Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            sticky_sessions: true,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        };
+
+        let send = |cookie: Option<String>| {
+            let state = state.clone();
+            async move {
+                let mut req = Request::builder();
+                if let Some(cookie) = cookie {
+                    req = req.header(header::COOKIE, format!("theme=dark; {cookie}"));
+                }
+                let req = req.body(Body::empty()).unwrap();
+                let res = proxy(State(state), client(), req).await.into_response();
+                let cookie = res.headers().get(header::SET_COOKIE).map(|cookie| {
+                    cookie
+                        .to_str()
+                        .unwrap()
+                        .split(';')
+                        .next()
+                        .unwrap()
+                        .to_owned()
+                });
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX);
+                (body.await.unwrap(), cookie)
+            }
+        };
+
+        let (body, cookie) = send(None).await;
+        assert_eq!("first", body);
+        assert_eq!(Some(format!("lb_upstream={}", addrs[0])), cookie);
+
+        // Round robin alone would send these to the second upstream
+        for _ in 0..2 {
+            let (body, set_cookie) = send(cookie.clone()).await;
+            assert_eq!("first", body);
+            assert_eq!(None, set_cookie);
+        }
+
+        let (body, cookie) = send(Some(String::from("lb_upstream=127.0.0.1:9"))).await;
+        assert_eq!(Some(format!("lb_upstream={}", addrs[1])), cookie);
+        assert_eq!("second", body);
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_upstream() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.socket");
+        let app = Router::new().route(
+            "/clientes/:id/extrato",
+            get(|Path(id): Path<u64>| async move { format!("extrato {id}") }),
+        );
+        tokio::spawn(axum_unix_socket::serve(path.clone(), app));
+        while !path.exists() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![path.to_str().unwrap().to_owned()],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(1)),
+            }),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let req = Request::builder()
+            .uri("/clientes/1/extrato")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(State(state), client(), req).await.into_response();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX);
+        assert_eq!("extrato 1", body.await.unwrap());
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let upstream = Router::new().route("/clientes/:id/extrato", get(|| async { "extrato" }));
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let state = app_state(
+            Arc::new(RoundRobin {
+                addrs: vec![addr.clone()],
+                req_counter: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new(1)),
+            }),
+            Arc::new(Health::new(1)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let buffer = Buffer::default();
+        let logger = Logger::new(Some(Level::Info), buffer.clone());
+        let mut app = app(state, Arc::new(logger));
+
+        let mut req = Request::builder()
+            .uri("/clientes/1/extrato")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(client());
+        let res = tower_service::Service::call(&mut app, req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let log = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let (_, line) = log.trim_end().split_once(' ').unwrap();
+        let (line, latency) = line.rsplit_once(" latency=").unwrap();
+        assert_eq!(
+            format!(
+                "level=info msg=access method=GET path=/clientes/1/extrato upstream={addr} status=200"
+            ),
+            line
+        );
+        assert!(latency.ends_with('s'));
+    }
+
+    #[test]
+    fn test_account_id() {
+        assert_eq!(Some(1), account_id("/clientes/1/transacoes"));
+        assert_eq!(Some(42), account_id("/clientes/42/extrato"));
+        assert_eq!(None, account_id("/clientes/abc/extrato"));
+        assert_eq!(None, account_id("/health"));
+    }
+
+    #[test]
+    fn test_account_balancer_routes_accounts_together() {
+        let balancer = RinhaAccountBalancer {
+            addrs: (0..8).map(|i| format!("app{i}")).collect(),
+            health: Arc::new(Health::new(8)),
+        };
+        let server = |path: &str| {
+            balancer.next_server(&Request::builder().uri(path).body(Body::empty()).unwrap())
+        };
+
+        for id in 1..=5 {
+            assert_eq!(
+                server(&format!("/clientes/{id}/transacoes")),
+                server(&format!("/clientes/{id}/extrato"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_routes_by_account() {
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let app = Router::new().fallback({
+                let addr = addr.clone();
+                move || async move { addr }
+            });
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            addrs.push(addr);
+        }
+        let balancer = Arc::new(RinhaAccountBalancer {
+            addrs,
+            health: Arc::new(Health::new(2)),
+        });
+        let state = app_state(
+            balancer.clone(),
+            Arc::new(Health::new(2)),
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(UpstreamConnector::default()),
+            DEFAULT_UPSTREAM_TIMEOUT,
+        );
+
+        let request = |path: &str| Request::builder().uri(path).body(Body::empty()).unwrap();
+        let mut reached = Vec::new();
+        for path in ["/clientes/1/transacoes", "/clientes/2/extrato"] {
+            let res = proxy(State(state.clone()), client(), request(path))
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(balancer.next_server(&request(path)), body);
+            reached.push(body);
+        }
+        // Both accounts landing on the same upstream wouldn't tell the paths apart from `/`
+        assert_ne!(reached[0], reached[1]);
+    }
+
+    #[test]
+    fn test_consistent_hash_remaps_removed_upstream_accounts() {
+        let servers = |addrs: &[&str]| {
+            let addrs = addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>();
+            let balancer = ConsistentHash::new(addrs.clone(), Arc::new(Health::new(addrs.len())));
+            (1..=3000)
+                .map(|id| {
+                    let path = format!("/clientes/{id}/extrato");
+                    balancer.next_server(&Request::builder().uri(path).body(Body::empty()).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let before = servers(&["app1", "app2", "app3"]);
+        let after = servers(&["app1", "app3"]);
+
+        let moved = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after);
+        assert!(moved.clone().all(|(before, _)| before == "app2"));
+
+        let moved = moved.count() as f64 / before.len() as f64;
+        assert!(
+            (0.25..0.42).contains(&moved),
+            "{moved} of the accounts moved"
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let addrs = vec![String::from("app1"), String::from("app2")];
+        let circuit_breaker = CircuitBreaker::new(&addrs, 3, Duration::from_millis(50));
+        let health = Health::new(2).with_circuit_breaker(circuit_breaker);
+
+        health.record("app1", false);
+        health.record("app1", false);
+        assert_eq!("app1", health.pick(&addrs, 0));
+
+        health.record("app1", false);
+        assert_eq!("app2", health.pick(&addrs, 0));
+        assert_eq!("app2", health.pick(&addrs, 1));
+
+        // Half open after the cooldown, a single failure opens it again
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!("app1", health.pick(&addrs, 0));
+        health.record("app1", false);
+        assert_eq!("app2", health.pick(&addrs, 0));
 
-    match http_client.request(req).await {
-        Ok(res) => Ok(res),
-        Err(_) => Err(StatusCode::BAD_GATEWAY),
+        std::thread::sleep(Duration::from_millis(50));
+        health.record("app1", true);
+        health.record("app1", false);
+        assert_eq!("app1", health.pick(&addrs, 0));
     }
 }
@@ -0,0 +1,100 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Header carrying the key clients send to make retries of a request safe.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Results of recent requests by their idempotency key, each forgotten once `window` has passed
+/// since it was first seen.
+#[derive(Debug)]
+pub struct IdempotencyKeys<V> {
+    window: Duration,
+    results: HashMap<String, V>,
+    expirations: VecDeque<(Instant, String)>,
+}
+
+impl<V> Default for IdempotencyKeys<V> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW)
+    }
+}
+
+impl<V> IdempotencyKeys<V> {
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            results: HashMap::new(),
+            expirations: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.expire();
+        self.results.get(key)
+    }
+
+    /// Remembers the result of a key. Inserting a key again replaces its result, but keeps the
+    /// time it expires.
+    pub fn insert(&mut self, key: String, result: V) {
+        self.expire();
+        if self.results.insert(key.clone(), result).is_none() {
+            self.expirations
+                .push_back((Instant::now() + self.window, key));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Keys are expired in the order they were inserted, so only the oldest ones are looked at.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some((_, key)) = self.expirations.front().filter(|(at, _)| *at <= now) {
+            self.results.remove(key);
+            self.expirations.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_get_inserted_key() {
+        let mut keys = IdempotencyKeys::default();
+        assert_eq!(None, keys.get("rinha"));
+
+        keys.insert(String::from("rinha"), 42);
+        assert_eq!(Some(&42), keys.get("rinha"));
+        assert_eq!(None, keys.get("backend"));
+    }
+
+    #[test]
+    fn test_keys_expire() {
+        let mut keys = IdempotencyKeys::new(Duration::from_millis(200));
+        keys.insert(String::from("old"), 1);
+        thread::sleep(Duration::from_millis(120));
+        keys.insert(String::from("new"), 2);
+        thread::sleep(Duration::from_millis(120));
+
+        assert_eq!(None, keys.get("old"));
+        assert_eq!(Some(&2), keys.get("new"));
+        assert_eq!(1, keys.len());
+
+        thread::sleep(Duration::from_millis(120));
+        assert_eq!(None, keys.get("new"));
+        assert!(keys.is_empty());
+    }
+}
@@ -1,8 +1,18 @@
-use std::{convert::TryFrom, fmt::Display};
+use std::{
+    collections::HashMap, convert::TryFrom, env, error::Error, fmt::Display, fs, str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
+pub use idempotency::{IdempotencyKeys, IDEMPOTENCY_KEY_HEADER};
+pub use logger::{Level, Logger};
+pub use metrics::{Registry, METRICS, METRICS_CONTENT_TYPE};
+
+mod idempotency;
+mod logger;
+mod metrics;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(try_from = "String")]
 pub struct Description(String);
@@ -11,7 +21,7 @@ impl TryFrom<String> for Description {
     type Error = &'static str;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.is_empty() || value.len() > 10 {
+        if value.is_empty() || value.chars().count() > 10 {
             Err("Descricao inválida")
         } else {
             Ok(Self(value))
@@ -19,6 +29,29 @@ impl TryFrom<String> for Description {
     }
 }
 
+/// The amount of a transaction, always a positive number of cents.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "i64")]
+pub struct Value(i64);
+
+impl TryFrom<i64> for Value {
+    type Error = &'static str;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        if value <= 0 {
+            Err("Valor inválido")
+        } else {
+            Ok(Self(value))
+        }
+    }
+}
+
+impl Value {
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionType {
     #[serde(rename = "c")]
@@ -30,7 +63,7 @@ pub enum TransactionType {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "valor")]
-    pub value: i64,
+    pub value: Value,
     #[serde(rename = "tipo")]
     pub kind: TransactionType,
     #[serde(rename = "descricao")]
@@ -39,7 +72,23 @@ pub struct Transaction {
     pub created_at: DateTime,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Transaction {
+    /// The balance after this transaction, failing when a debit goes over the limit or the
+    /// balance would overflow.
+    pub fn apply(&self, balance: i64, limit: i64) -> Result<i64, &'static str> {
+        let value = self.value.get();
+        match self.kind {
+            TransactionType::Credit => balance.checked_add(value).ok_or("Saldo inválido"),
+            TransactionType::Debit => match balance.checked_sub(value) {
+                Some(balance) if balance >= -limit => Ok(balance),
+                Some(_) => Err("Não tem limite o suficiente"),
+                None => Err("Saldo inválido"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct DateTime(#[serde(with = "time::serde::rfc3339")] OffsetDateTime);
 
 impl Default for DateTime {
@@ -54,8 +103,201 @@ impl Display for DateTime {
     }
 }
 
+impl FromStr for DateTime {
+    type Err = time::error::Parse;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_rfc3339(s)
+    }
+}
+
 impl DateTime {
     pub fn now() -> DateTime {
         Default::default()
     }
+
+    pub fn parse_rfc3339(value: &str) -> Result<DateTime, time::error::Parse> {
+        OffsetDateTime::parse(value, &Rfc3339).map(Self)
+    }
+}
+
+pub type AccountId = u32;
+
+/// The accounts of the Rinha and their limits, used when none are configured.
+pub const DEFAULT_ACCOUNTS: [(AccountId, i64); 5] = [
+    (1, 100_000),
+    (2, 80_000),
+    (3, 1_000_000),
+    (4, 10_000_000),
+    (5, 500_000),
+];
+
+/// Loads the limit of each account, keyed by the account id, from the `ACCOUNTS` env var or from
+/// the file at `ACCOUNTS_FILE`, falling back to [`DEFAULT_ACCOUNTS`]. Both hold a JSON object like
+/// `{"1": 100000, "2": 80000}`.
+pub fn load_accounts() -> Result<HashMap<AccountId, i64>, Box<dyn Error>> {
+    if let Ok(config) = env::var("ACCOUNTS") {
+        return Ok(parse_accounts(&config)?);
+    }
+
+    if let Ok(path) = env::var("ACCOUNTS_FILE") {
+        return Ok(parse_accounts(&fs::read_to_string(path)?)?);
+    }
+
+    Ok(HashMap::from(DEFAULT_ACCOUNTS))
+}
+
+pub fn parse_accounts(config: &str) -> Result<HashMap<AccountId, i64>, serde_json::Error> {
+    serde_json::from_str(config)
+}
+
+/// Query params paging backwards through the transactions of an account. `before` takes the
+/// cursor returned along with the previous page.
+#[derive(Debug, Default, Deserialize)]
+pub struct Pagination {
+    pub limit: Option<usize>,
+    pub before: Option<u64>,
+}
+
+impl Pagination {
+    pub const DEFAULT_LIMIT: usize = 10;
+    pub const MAX_LIMIT: usize = 100;
+
+    /// Whether any of the params were given, as opposed to just the latest transactions.
+    pub fn is_requested(&self) -> bool {
+        self.limit.is_some() || self.before.is_some()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+}
+
+/// Query params filtering transactions by when they were made, from `from` (inclusive) until `to`
+/// (exclusive). A missing bound leaves that side open.
+#[derive(Debug, Default, Deserialize)]
+pub struct DateRange {
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+}
+
+impl DateRange {
+    pub fn is_requested(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+
+    /// Whether the date comes before the start of the range.
+    pub fn is_before(&self, date: &DateTime) -> bool {
+        self.from.as_ref().is_some_and(|from| date < from)
+    }
+
+    /// Whether the date comes at or after the end of the range.
+    pub fn is_after(&self, date: &DateTime) -> bool {
+        self.to.as_ref().is_some_and(|to| date >= to)
+    }
+
+    pub fn contains(&self, date: &DateTime) -> bool {
+        !self.is_before(date) && !self.is_after(date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_description_counts_characters() {
+        assert!(Description::try_from(String::from("ação!!")).is_ok());
+        assert!(Description::try_from(String::from("transações")).is_ok());
+        assert!(Description::try_from(String::from("ãããããããããã")).is_ok());
+        assert!(Description::try_from(String::from("ããããããããããã")).is_err());
+        assert!(Description::try_from(String::from("promoções!!")).is_err());
+        assert!(Description::try_from(String::new()).is_err());
+    }
+
+    #[test]
+    fn test_value_must_be_positive() {
+        let transaction = |value: i64| {
+            serde_json::from_str::<Transaction>(&format!(
+                r#"{{"valor": {value}, "tipo": "c", "descricao": "Rinha"}}"#
+            ))
+        };
+
+        assert_eq!(5, transaction(5).unwrap().value.get());
+        assert!(transaction(0).is_err());
+        assert!(transaction(-5).is_err());
+    }
+
+    fn transaction(kind: TransactionType, value: i64) -> Transaction {
+        Transaction {
+            value: Value::try_from(value).unwrap(),
+            kind,
+            description: Description::try_from(String::from("Rinha")).unwrap(),
+            created_at: DateTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_date_time_round_trip() {
+        let now = DateTime::now();
+        let parsed = now.to_string().parse::<DateTime>().unwrap();
+        assert_eq!(now.0, parsed.0);
+
+        let date = DateTime::parse_rfc3339("2024-02-01T12:30:00.5-03:00").unwrap();
+        assert_eq!("2024-02-01T12:30:00.5-03:00", date.to_string());
+        assert!("01/02/2024".parse::<DateTime>().is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let credit = transaction(TransactionType::Credit, 100);
+        let debit = transaction(TransactionType::Debit, 100);
+
+        assert_eq!(Ok(150), credit.apply(50, 0));
+        assert_eq!(Ok(-50), debit.apply(50, 50));
+        assert!(debit.apply(50, 49).is_err());
+    }
+
+    #[test]
+    fn test_apply_overflow() {
+        let credit = transaction(TransactionType::Credit, 100);
+        assert!(credit.apply(i64::MAX - 100, 0).is_ok());
+        assert!(credit.apply(i64::MAX - 99, 0).is_err());
+
+        let debit = transaction(TransactionType::Debit, i64::MAX);
+        assert!(debit.apply(-2, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_parse_accounts() {
+        let accounts = parse_accounts(r#"{"1": 1000, "7": 0, "4200": 5000000}"#).unwrap();
+        assert_eq!(
+            HashMap::from([(1, 1000), (7, 0), (4200, 5_000_000)]),
+            accounts
+        );
+
+        assert!(parse_accounts(r#"{"rinha": 1000}"#).is_err());
+        assert!(parse_accounts(r#"{"1": "1000"}"#).is_err());
+    }
+
+    #[test]
+    fn test_date_range_bounds() {
+        let date = |value: &str| DateTime::parse_rfc3339(value).unwrap();
+        let range = DateRange {
+            from: Some(date("2024-02-01T00:00:00Z")),
+            to: Some(date("2024-02-02T00:00:00Z")),
+        };
+
+        assert!(range.contains(&date("2024-02-01T00:00:00Z")));
+        assert!(range.contains(&date("2024-02-01T23:59:59Z")));
+        assert!(!range.contains(&date("2024-02-02T00:00:00Z")));
+        assert!(range.is_before(&date("2024-01-31T23:59:59Z")));
+        assert!(range.is_after(&date("2024-02-02T00:00:00Z")));
+
+        let open = DateRange::default();
+        assert!(!open.is_requested());
+        assert!(open.contains(&date("1970-01-01T00:00:00Z")));
+    }
 }
@@ -0,0 +1,171 @@
+use std::{
+    env,
+    fmt::{Display, Write as _},
+    io::{self, Write},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// How important a log line is, from the most to the least important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("invalid log level: {level}")),
+        }
+    }
+}
+
+/// Writes log lines as `key=value` pairs, the logfmt format, skipping the ones less important than
+/// its level. Without a level nothing is logged.
+pub struct Logger {
+    level: Option<Level>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(level: Option<Level>, out: impl Write + Send + 'static) -> Self {
+        Self {
+            level,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Logs to stderr at the level in `LOG_LEVEL`, which can also be `off`. Defaults to `warn`,
+    /// so only failures are logged while under load.
+    pub fn from_env() -> Self {
+        let level = match env::var("LOG_LEVEL") {
+            Ok(level) if level.eq_ignore_ascii_case("off") => None,
+            Ok(level) => level.parse().ok().or(Some(Level::Warn)),
+            Err(_) => Some(Level::Warn),
+        };
+        Self::new(level, io::stderr())
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        self.level.is_some_and(|max| level <= max)
+    }
+
+    pub fn log(&self, level: Level, msg: &str, fields: &[(&str, &dyn Display)]) {
+        if !self.enabled(level) {
+            return;
+        }
+
+        let mut line = String::new();
+        if let Ok(now) = OffsetDateTime::now_utc().format(&Rfc3339) {
+            write!(line, "ts={now} ").unwrap();
+        }
+        write!(line, "level={} msg={}", level.as_str(), quoted(msg)).unwrap();
+        for (key, value) in fields {
+            write!(line, " {key}={}", quoted(&value.to_string())).unwrap();
+        }
+        line.push('\n');
+
+        let mut out = self.out.lock().unwrap_or_else(|err| err.into_inner());
+        out.write_all(line.as_bytes()).ok();
+    }
+}
+
+/// Quotes values that would otherwise be ambiguous, like ones with spaces.
+fn quoted(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control());
+    if plain {
+        value.to_owned()
+    } else {
+        format!("{value:?}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_fields() {
+        let buffer = Buffer::default();
+        let logger = Logger::new(Some(Level::Info), buffer.clone());
+
+        logger.log(
+            Level::Info,
+            "access",
+            &[("path", &"/clientes/1/extrato"), ("status", &200)],
+        );
+        logger.log(
+            Level::Warn,
+            "failed to connect",
+            &[("error", &"no \"route\"")],
+        );
+        logger.log(Level::Debug, "skipped", &[]);
+
+        let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = out
+            .lines()
+            .map(|line| line.split_once(' ').unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        assert!(lines.iter().all(|(ts, _)| ts.starts_with("ts=")));
+        assert_eq!(
+            "level=info msg=access path=/clientes/1/extrato status=200",
+            lines[0].1
+        );
+        assert_eq!(
+            "level=warn msg=\"failed to connect\" error=\"no \\\"route\\\"\"",
+            lines[1].1
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(Ok(Level::Warn), "WARN".parse());
+        assert!("loud".parse::<Level>().is_err());
+
+        let logger = Logger::new(Some(Level::Warn), io::sink());
+        assert!(logger.enabled(Level::Error));
+        assert!(!logger.enabled(Level::Info));
+        assert!(!Logger::new(None, io::sink()).enabled(Level::Error));
+    }
+}
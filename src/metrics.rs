@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/// The registry of the running process, rendered by the `/metrics` endpoints.
+pub static METRICS: Registry = Registry::new();
+
+/// Content type of the rendered metrics, version 0.0.4 of the Prometheus text format.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds, in seconds, of the buckets durations are counted in.
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+type Series = (&'static str, String);
+
+/// Counters and histograms rendered in the Prometheus text format. A series is told apart by its
+/// name and labels, and is created the first time it's touched.
+#[derive(Debug)]
+pub struct Registry {
+    counters: Mutex<BTreeMap<Series, u64>>,
+    histograms: Mutex<BTreeMap<Series, Histogram>>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Self {
+            counters: Mutex::new(BTreeMap::new()),
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn increment(&self, name: &'static str, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        *counters.entry((name, format_labels(labels))).or_default() += 1;
+    }
+
+    /// Current value of a counter, zero when it was never incremented.
+    pub fn counter(&self, name: &'static str, labels: &[(&str, &str)]) -> u64 {
+        let counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        counters
+            .get(&(name, format_labels(labels)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Records a duration, in seconds, in a histogram.
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], seconds: f64) {
+        let mut histograms = self
+            .histograms
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let histogram = histograms.entry((name, format_labels(labels))).or_default();
+
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        let mut last_name = None;
+        for ((name, labels), value) in counters.iter() {
+            if last_name != Some(name) {
+                writeln!(out, "# TYPE {name} counter").unwrap();
+                last_name = Some(name);
+            }
+            writeln!(out, "{name}{} {value}", braced(labels)).unwrap();
+        }
+        drop(counters);
+
+        let histograms = self
+            .histograms
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut last_name = None;
+        for ((name, labels), histogram) in histograms.iter() {
+            if last_name != Some(name) {
+                writeln!(out, "# TYPE {name} histogram").unwrap();
+                last_name = Some(name);
+            }
+
+            let separator = if labels.is_empty() { "" } else { "," };
+            for (bucket, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                writeln!(
+                    out,
+                    "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {bucket}"
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+            writeln!(out, "{name}_sum{} {}", braced(labels), histogram.sum).unwrap();
+            writeln!(out, "{name}_count{} {}", braced(labels), histogram.count).unwrap();
+        }
+
+        out
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        let registry = Registry::new();
+        registry.increment("requests_total", &[]);
+        registry.increment("transactions_total", &[("account", "1")]);
+        registry.increment("transactions_total", &[("account", "1")]);
+        registry.increment("transactions_total", &[("account", "\"2\"")]);
+
+        assert_eq!(
+            2,
+            registry.counter("transactions_total", &[("account", "1")])
+        );
+        assert_eq!(
+            0,
+            registry.counter("transactions_total", &[("account", "3")])
+        );
+        assert_eq!(
+            "# TYPE requests_total counter\n\
+             requests_total 1\n\
+             # TYPE transactions_total counter\n\
+             transactions_total{account=\"1\"} 2\n\
+             transactions_total{account=\"\\\"2\\\"\"} 1\n",
+            registry.render()
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let registry = Registry::new();
+        registry.observe("duration_seconds", &[("route", "/")], 0.003);
+        registry.observe("duration_seconds", &[("route", "/")], 5.0);
+
+        let rendered = registry.render();
+        assert!(rendered.starts_with("# TYPE duration_seconds histogram\n"));
+        assert!(rendered.contains("duration_seconds_bucket{route=\"/\",le=\"0.0025\"} 0\n"));
+        assert!(rendered.contains("duration_seconds_bucket{route=\"/\",le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("duration_seconds_bucket{route=\"/\",le=\"2.5\"} 1\n"));
+        assert!(rendered.contains("duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("duration_seconds_sum{route=\"/\"} 5.003\n"));
+        assert!(rendered.contains("duration_seconds_count{route=\"/\"} 2\n"));
+    }
+}